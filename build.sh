@@ -0,0 +1,14 @@
+#!/bin/bash
+# Builds the charm binaries from source and copies them into the charms. Run this before
+# deploying or publishing the charms, the binaries are not kept in the repository.
+set -e
+
+cd "$(dirname "$0")"
+
+(cd create-crushmap && cargo build --release --locked)
+install -m 0755 create-crushmap/target/release/create-crushmap \
+    charm-dct-controller/actions/create-crushmap
+
+(cd discover-neighbors && cargo build --release --locked)
+install -m 0755 discover-neighbors/target/release/controller-relation-changed \
+    charm-dct-node/hooks/controller-relation-changed
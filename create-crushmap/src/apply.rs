@@ -0,0 +1,292 @@
+use crushtool::CrushMap;
+use juju;
+use log::LogLevel;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use buckets;
+use cluster::Cluster;
+use state;
+use validate;
+
+// Seconds a ceph command gets before it is killed, so a cluster that doesn't answer can't hang
+// the action or hook waiting on it
+const CEPH_TIMEOUT: u64 = 60;
+
+// Settings for applying a crushmap, read from the charm config
+pub struct ApplyOptions {
+    // How long to watch cluster health after the new map is set, in seconds
+    pub watch_window: u64,
+    // How often to check health during the watch window, in seconds
+    pub check_interval: u64,
+}
+
+// Ceph health states, ordered from best to worst
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Health {
+    Ok,
+    Warn,
+    Err,
+}
+
+// Applies the cluster's dct_crushmap to it.
+//
+// The new map is validated and checked against the running one first, then the running map is
+// saved with a timestamp so it can be restored. After `ceph osd setcrushmap` we poll
+// `ceph health` for the watch window. HEALTH_WARN is expected while placement groups remap and
+// backfill, so only a cluster that reaches HEALTH_ERR when it wasn't there before counts as
+// degraded, in which case the backup is set again. Returns the path of the backup on success.
+pub fn apply_crushmap(cluster: &Cluster,
+                      new_map_bytes: &[u8],
+                      options: &ApplyOptions)
+                      -> Result<PathBuf, String> {
+    let backup_path = try!(backup_current_map(cluster));
+    juju::log(format!("Saved the running crushmap to {}", backup_path.display()),
+              Some(LogLevel::Info));
+
+    let backup_bytes = try!(read_file(&backup_path));
+    let current_map = try!(::crushtool::decode_crushmap(&backup_bytes[..]));
+    let new_map = try!(::crushtool::decode_crushmap(new_map_bytes));
+    let report = validate::validate(&new_map);
+    if !report.is_valid() {
+        return Err(format!("The new crushmap is invalid: {}", report.errors.join("; ")));
+    }
+    try!(check_map(&current_map, &new_map));
+
+    let baseline = try!(cluster_health(cluster));
+    if baseline == Health::Err {
+        return Err("Cluster is already in HEALTH_ERR, refusing to change the crushmap"
+            .to_string());
+    }
+
+    try!(set_crushmap(cluster, &cluster.path("dct_crushmap")));
+    juju::log("New crushmap set, watching cluster health", Some(LogLevel::Info));
+
+    match watch_health(cluster, baseline, options) {
+        Ok(()) => Ok(backup_path),
+        Err(reason) => {
+            juju::log(format!("{}, restoring {}", reason, backup_path.display()),
+                      Some(LogLevel::Error));
+            match set_crushmap(cluster, &backup_path) {
+                Ok(()) => Err(format!("{}. The previous crushmap was restored.", reason)),
+                Err(e) => {
+                    Err(format!("{}. Restoring {} also failed: {}",
+                                reason,
+                                backup_path.display(),
+                                e))
+                }
+            }
+        }
+    }
+}
+
+// Checks the new map against the running one. Every device the cluster knows about has to be
+// placed somewhere in the new map, otherwise its data would be orphaned.
+fn check_map(current_map: &CrushMap, new_map: &CrushMap) -> Result<(), String> {
+    if new_map.buckets.iter().all(|bucket| buckets::header(bucket).is_none()) {
+        return Err("The new crushmap has no buckets".to_string());
+    }
+
+    let placed: HashSet<i32> = devices(new_map);
+    let mut missing: Vec<i32> = devices(current_map)
+        .into_iter()
+        .filter(|osd| !placed.contains(osd))
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        let names: Vec<String> = missing.iter().map(|osd| format!("osd.{}", osd)).collect();
+        return Err(format!("The new crushmap does not place {}", names.join(", ")));
+    }
+
+    Ok(())
+}
+
+fn devices(map: &CrushMap) -> HashSet<i32> {
+    let mut devices: HashSet<i32> = HashSet::new();
+    for bucket in &map.buckets {
+        if let Some(header) = buckets::header(bucket) {
+            devices.extend(header.items.iter().map(|&(item, _)| item).filter(|&item| item >= 0));
+        }
+    }
+    devices
+}
+
+// Saves the running map as a backup snapshot, see state.rs
+fn backup_current_map(cluster: &Cluster) -> Result<PathBuf, String> {
+    let path = try!(state::snapshot_path(cluster, "backup"));
+    try!(ceph(cluster, &["osd", "getcrushmap", "-o", &path.to_string_lossy()]));
+    state::prune(cluster, "backup");
+    Ok(path)
+}
+
+fn set_crushmap(cluster: &Cluster, path: &Path) -> Result<(), String> {
+    try!(ceph(cluster, &["osd", "setcrushmap", "-i", &path.to_string_lossy()]));
+    Ok(())
+}
+
+// Polls health until the window closes, failing as soon as the cluster gets worse than
+// HEALTH_WARN
+fn watch_health(cluster: &Cluster, baseline: Health, options: &ApplyOptions) -> Result<(), String> {
+    let started = Instant::now();
+    let window = Duration::from_secs(options.watch_window);
+    // An interval of 0 would poll ceph as fast as it answers
+    let interval = Duration::from_secs(cmp::max(options.check_interval, 1));
+    loop {
+        let health = try!(cluster_health(cluster));
+        println!("Cluster health: {:?}", health);
+        if degraded(baseline, health) {
+            return Err("Cluster health degraded to HEALTH_ERR".to_string());
+        }
+        if started.elapsed() >= window {
+            return Ok(());
+        }
+        thread::sleep(interval);
+    }
+}
+
+// HEALTH_WARN is expected while data moves to its new place, so only reaching HEALTH_ERR counts,
+// and only if the cluster wasn't there before the map changed
+fn degraded(baseline: Health, health: Health) -> bool {
+    health == Health::Err && baseline != Health::Err
+}
+
+pub fn cluster_health(cluster: &Cluster) -> Result<Health, String> {
+    parse_health(&try!(ceph(cluster, &["health"])))
+}
+
+fn parse_health(output: &str) -> Result<Health, String> {
+    match output.split_whitespace().next() {
+        Some("HEALTH_OK") => Ok(Health::Ok),
+        Some("HEALTH_WARN") => Ok(Health::Warn),
+        Some("HEALTH_ERR") => Ok(Health::Err),
+        _ => Err(format!("Could not understand ceph health output: {}", output.trim())),
+    }
+}
+
+// Runs a ceph command against the cluster and returns its stdout, turning a non-zero exit into
+// an error. The command is killed if it doesn't finish within CEPH_TIMEOUT.
+pub fn ceph(cluster: &Cluster, args: &[&str]) -> Result<String, String> {
+    let args = cluster.ceph_args(args);
+    let mut child = try!(Command::new("ceph")
+        .current_dir("/tmp")
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run ceph {}: {}", args.join(" "), e)));
+
+    // Both pipes are read while ceph runs, so a chatty command can't fill one and hang
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() >= Duration::from_secs(CEPH_TIMEOUT) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("ceph {} did not finish within {} seconds",
+                                   args.join(" "),
+                                   CEPH_TIMEOUT));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(200)),
+            Err(e) => return Err(format!("Failed waiting for ceph {}: {}", args.join(" "), e)),
+        }
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    if !status.success() {
+        return Err(format!("ceph {} failed: {}",
+                           args.join(" "),
+                           String::from_utf8_lossy(&stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+// Reads a child's pipe to the end on its own thread
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes: Vec<u8> = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut file = try!(File::open(path).map_err(|e| e.to_string()));
+    let mut bytes: Vec<u8> = Vec::new();
+    try!(file.read_to_end(&mut bytes).map_err(|e| e.to_string()));
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use text;
+
+    // osd.0 and osd.1 on node1, osd.2 on node2, with `hosts` picking which hosts the root holds
+    fn map(hosts: &str) -> CrushMap {
+        text::parse(&format!("device 0 osd.0\ndevice 1 osd.1\ndevice 2 osd.2\ntype 0 osd\n\
+                              type 1 host\ntype 10 root\n\
+                              host node1 {{\n\tid -2\n\talg straw2\n\titem osd.0 weight 1.000\n\
+                              \titem osd.1 weight 1.000\n}}\n\
+                              host node2 {{\n\tid -3\n\talg straw2\n\titem osd.2 weight 1.000\n}}\n\
+                              root default {{\n\tid -1\n\talg straw2\n{}}}\n",
+                             hosts))
+            .unwrap()
+    }
+
+    #[test]
+    fn maps_placing_every_device_pass() {
+        let current = map("\titem node1 weight 2.000\n\titem node2 weight 1.000\n");
+        assert_eq!(check_map(&current, &current.clone()), Ok(()));
+    }
+
+    #[test]
+    fn unplaced_devices_are_named() {
+        let current = map("\titem node1 weight 2.000\n\titem node2 weight 1.000\n");
+        let mut new_map = current.clone();
+        buckets::remove(&mut new_map.buckets, -3);
+        assert_eq!(check_map(&current, &new_map),
+                   Err("The new crushmap does not place osd.2".to_string()));
+    }
+
+    #[test]
+    fn maps_without_buckets_are_refused() {
+        let current = map("\titem node1 weight 2.000\n");
+        let mut new_map = current.clone();
+        for id in -3..0 {
+            buckets::remove(&mut new_map.buckets, id);
+        }
+        assert_eq!(check_map(&current, &new_map),
+                   Err("The new crushmap has no buckets".to_string()));
+    }
+
+    #[test]
+    fn only_a_new_health_err_is_degraded() {
+        assert!(degraded(Health::Ok, Health::Err));
+        assert!(degraded(Health::Warn, Health::Err));
+        assert!(!degraded(Health::Ok, Health::Warn));
+        assert!(!degraded(Health::Warn, Health::Ok));
+        assert!(!degraded(Health::Err, Health::Err));
+    }
+
+    #[test]
+    fn health_is_read_from_the_first_word() {
+        assert_eq!(parse_health("HEALTH_OK\n"), Ok(Health::Ok));
+        assert_eq!(parse_health("HEALTH_WARN 12 pgs backfilling"), Ok(Health::Warn));
+        assert_eq!(parse_health("HEALTH_ERR 1 pgs inconsistent"), Ok(Health::Err));
+        assert!(parse_health("").is_err());
+        assert!(parse_health("health ok").is_err());
+    }
+}
@@ -0,0 +1,574 @@
+use crushtool::{Bucket, BucketAlg, BucketTypes, CrushBucketList, CrushBucketStraw,
+                CrushBucketStraw2, CrushBucketTree, CrushBucketUniform, CrushHash, OpCode};
+use std::cmp::Reverse;
+
+// Helpers for working with crushtool buckets without matching on every algorithm each time.
+// Unknown buckets are the empty slots Ceph leaves in the bucket array and have no header.
+
+// An empty bucket of the given algorithm, ready for set_items
+pub fn new_bucket(id: i32, bucket_type: OpCode, alg: BucketAlg) -> BucketTypes {
+    let bucket = Bucket {
+        id: id,
+        bucket_type: bucket_type,
+        alg: alg.clone(),
+        hash: CrushHash::RJenkins1,
+        weight: 0,
+        size: 0,
+        items: Vec::new(),
+        perm_n: 0,
+        perm: 0,
+    };
+    match alg {
+        BucketAlg::Uniform => {
+            BucketTypes::Uniform(CrushBucketUniform {
+                bucket: bucket,
+                item_weight: 0,
+            })
+        }
+        BucketAlg::List => {
+            BucketTypes::List(CrushBucketList {
+                bucket: bucket,
+                item_weights: Vec::new(),
+            })
+        }
+        BucketAlg::Tree => {
+            BucketTypes::Tree(CrushBucketTree {
+                bucket: bucket,
+                num_nodes: 0,
+                node_weights: Vec::new(),
+            })
+        }
+        BucketAlg::Straw => {
+            BucketTypes::Straw(CrushBucketStraw {
+                bucket: bucket,
+                item_weights: Vec::new(),
+            })
+        }
+        BucketAlg::Straw2 => {
+            BucketTypes::Straw2(CrushBucketStraw2 {
+                bucket: bucket,
+                item_weights: Vec::new(),
+            })
+        }
+    }
+}
+
+pub fn header(bucket: &BucketTypes) -> Option<&Bucket> {
+    match *bucket {
+        BucketTypes::Uniform(ref uniform) => Some(&uniform.bucket),
+        BucketTypes::List(ref list) => Some(&list.bucket),
+        BucketTypes::Tree(ref tree) => Some(&tree.bucket),
+        BucketTypes::Straw(ref straw) => Some(&straw.bucket),
+        BucketTypes::Straw2(ref straw2) => Some(&straw2.bucket),
+        BucketTypes::Unknown => None,
+    }
+}
+
+pub fn header_mut(bucket: &mut BucketTypes) -> Option<&mut Bucket> {
+    match *bucket {
+        BucketTypes::Uniform(ref mut uniform) => Some(&mut uniform.bucket),
+        BucketTypes::List(ref mut list) => Some(&mut list.bucket),
+        BucketTypes::Tree(ref mut tree) => Some(&mut tree.bucket),
+        BucketTypes::Straw(ref mut straw) => Some(&mut straw.bucket),
+        BucketTypes::Straw2(ref mut straw2) => Some(&mut straw2.bucket),
+        BucketTypes::Unknown => None,
+    }
+}
+
+pub fn id(bucket: &BucketTypes) -> Option<i32> {
+    header(bucket).map(|b| b.id)
+}
+
+pub fn weight(bucket: &BucketTypes) -> u32 {
+    header(bucket).map(|b| b.weight).unwrap_or(0)
+}
+
+// The 16.16 fixed point weight of every item in the bucket, in item order.
+// Tree buckets store their weights in the interior nodes of the tree, where item i lives at
+// node 2i+1.
+pub fn item_weights(bucket: &BucketTypes) -> Vec<u32> {
+    match *bucket {
+        BucketTypes::Uniform(ref uniform) => {
+            uniform.bucket.items.iter().map(|_| uniform.item_weight).collect()
+        }
+        BucketTypes::List(ref list) => list.item_weights.iter().map(|&(w, _)| w).collect(),
+        BucketTypes::Tree(ref tree) => {
+            (0..tree.bucket.items.len())
+                .map(|i| *tree.node_weights.get(2 * i + 1).unwrap_or(&0))
+                .collect()
+        }
+        BucketTypes::Straw(ref straw) => straw.item_weights.iter().map(|&(w, _)| w).collect(),
+        BucketTypes::Straw2(ref straw2) => straw2.item_weights.clone(),
+        BucketTypes::Unknown => Vec::new(),
+    }
+}
+
+pub fn find(buckets: &[BucketTypes], id: i32) -> Option<&BucketTypes> {
+    buckets.iter().find(|bucket| self::id(bucket) == Some(id))
+}
+
+pub fn find_mut(buckets: &mut [BucketTypes], id: i32) -> Option<&mut BucketTypes> {
+    buckets.iter_mut().find(|bucket| self::id(bucket) == Some(id))
+}
+
+// Finds the bucket that directly holds the given item, if any
+pub fn parent_of(buckets: &[BucketTypes], item: i32) -> Option<i32> {
+    for bucket in buckets {
+        if let Some(header) = header(bucket) {
+            if header.items.iter().any(|&(child, _)| child == item) {
+                return Some(header.id);
+            }
+        }
+    }
+    None
+}
+
+// Walks up from a bucket until reaching the root it hangs from
+pub fn root_of(buckets: &[BucketTypes], id: i32) -> i32 {
+    let mut current = id;
+    let mut depth = 0;
+    while let Some(parent) = parent_of(buckets, current) {
+        current = parent;
+        depth += 1;
+        if depth > buckets.len() {
+            break;
+        }
+    }
+    current
+}
+
+// Replaces the items of a bucket and rebuilds the algorithm specific weight fields to match.
+// Straw lengths depend on the map's straw_calc_version tunable, which is 0 when unset.
+pub fn set_items(bucket: &mut BucketTypes,
+                 items: Vec<(i32, Option<String>)>,
+                 weights: Vec<u32>,
+                 straw_calc_version: u8)
+                 -> Result<(), String> {
+    let total_weight: u32 = weights.iter().sum();
+    match *bucket {
+        BucketTypes::Uniform(ref mut uniform) => {
+            if weights.iter().any(|&w| w != weights[0]) {
+                return Err(format!("Uniform bucket {} cannot hold items of differing weights",
+                                   uniform.bucket.id));
+            }
+            uniform.item_weight = weights.first().cloned().unwrap_or(uniform.item_weight);
+        }
+        BucketTypes::List(ref mut list) => {
+            let mut running: u32 = 0;
+            list.item_weights = weights.iter()
+                .map(|&w| {
+                    running += w;
+                    (w, running)
+                })
+                .collect();
+        }
+        BucketTypes::Tree(ref mut tree) => {
+            let node_weights = try!(tree_node_weights(&weights)
+                .ok_or(format!("Tree bucket {} has too many items", tree.bucket.id)));
+            tree.num_nodes = node_weights.len() as u8;
+            tree.node_weights = node_weights;
+        }
+        BucketTypes::Straw(ref mut straw) => {
+            let straws = straw_lengths(&weights, straw_calc_version);
+            straw.item_weights = weights.iter().cloned().zip(straws).collect();
+        }
+        BucketTypes::Straw2(ref mut straw2) => {
+            straw2.item_weights = weights.clone();
+        }
+        BucketTypes::Unknown => return Err("Cannot add items to an empty bucket slot".to_string()),
+    }
+    let header = header_mut(bucket).unwrap();
+    header.size = items.len() as u32;
+    header.perm = items.len() as u32;
+    header.perm_n = 0;
+    header.items = items;
+    header.weight = total_weight;
+    Ok(())
+}
+
+// Straw lengths for a straw bucket, calculated the way crush_calc_straw does. Items are visited
+// from lightest to heaviest and each step up in weight stretches the straws of the heavier items
+// so they win in proportion to their weight. Version 0 is the original calculation, which gets
+// the proportions wrong when several items share a weight; version 1 fixes that.
+fn straw_lengths(weights: &[u32], straw_calc_version: u8) -> Vec<u32> {
+    let size = weights.len();
+    let mut straws: Vec<u32> = vec![0; size];
+
+    // Stable sort by weight, as the insertion sort in Ceph is
+    let mut order: Vec<usize> = (0..size).collect();
+    order.sort_by_key(|&i| weights[i]);
+
+    let mut numleft = size as f64;
+    let mut straw: f64 = 1.0;
+    let mut wbelow: f64 = 0.0;
+    let mut lastw: f64 = 0.0;
+
+    let mut i = 0;
+    while i < size {
+        if weights[order[i]] == 0 {
+            straws[order[i]] = 0;
+            i += 1;
+            if straw_calc_version >= 1 {
+                numleft -= 1.0;
+            }
+            continue;
+        }
+        straws[order[i]] = (straw * 65536.0) as u32;
+        i += 1;
+        if i == size {
+            break;
+        }
+
+        let previous = weights[order[i - 1]] as f64;
+        let current = weights[order[i]] as f64;
+        if straw_calc_version == 0 {
+            if current == previous {
+                continue;
+            }
+            wbelow += (previous - lastw) * numleft;
+            for j in i..size {
+                if weights[order[j]] == weights[order[i]] {
+                    numleft -= 1.0;
+                } else {
+                    break;
+                }
+            }
+        } else {
+            wbelow += (previous - lastw) * numleft;
+            numleft -= 1.0;
+        }
+        let wnext = numleft * (current - previous);
+        let pbelow = wbelow / (wbelow + wnext);
+        straw *= (1.0 / pbelow).powf(1.0 / numleft);
+        lastw = previous;
+    }
+    straws
+}
+
+// Lays out tree bucket weights the way crush_make_tree_bucket does. Item i is the leaf at node
+// 2i+1 and every interior node holds the sum of the leaves below it. Returns None when the tree
+// would need more nodes than the encoding can count.
+fn tree_node_weights(weights: &[u32]) -> Option<Vec<u32>> {
+    if weights.is_empty() {
+        return Some(Vec::new());
+    }
+    let mut depth: u32 = 1;
+    let mut t = weights.len() - 1;
+    while t > 0 {
+        t >>= 1;
+        depth += 1;
+    }
+    if depth > 7 {
+        return None;
+    }
+    let mut node_weights: Vec<u32> = vec![0; 1 << depth];
+    for (i, &weight) in weights.iter().enumerate() {
+        let mut node = 2 * i + 1;
+        node_weights[node] = weight;
+        for _ in 1..depth {
+            let mut height = 0;
+            while ((node >> height) & 1) == 0 {
+                height += 1;
+            }
+            node = if (node & (1 << (height + 1))) != 0 {
+                node - (1 << height)
+            } else {
+                node + (1 << height)
+            };
+            node_weights[node] += weight;
+        }
+    }
+    Some(node_weights)
+}
+
+pub fn alg_from_name(name: &str) -> Option<BucketAlg> {
+    match name {
+        "uniform" => Some(BucketAlg::Uniform),
+        "list" => Some(BucketAlg::List),
+        "tree" => Some(BucketAlg::Tree),
+        "straw" => Some(BucketAlg::Straw),
+        "straw2" => Some(BucketAlg::Straw2),
+        _ => None,
+    }
+}
+
+// The algorithm most of the existing buckets use, so new buckets can match them. Ties go to the
+// newer algorithm.
+pub fn most_common_alg(buckets: &[BucketTypes]) -> Option<BucketAlg> {
+    let mut counts: Vec<(usize, u8, BucketAlg)> = Vec::new();
+    for bucket in buckets {
+        if let Some(header) = header(bucket) {
+            match counts.iter().position(|(_, _, alg)| *alg == header.alg) {
+                Some(index) => counts[index].0 += 1,
+                None => counts.push((1, header.alg.clone() as u8, header.alg.clone())),
+            }
+        }
+    }
+    counts.sort_by_key(|&(count, code, _)| Reverse((count, code)));
+    counts.into_iter().next().map(|(_, _, alg)| alg)
+}
+
+// Bucket types are stored as an OpCode by crushtool, so only type ids with a matching OpCode
+// value can be encoded. That covers the standard types except pdu (5).
+pub fn type_code(type_id: i32) -> Option<OpCode> {
+    match type_id {
+        0 => Some(OpCode::Noop),
+        1 => Some(OpCode::Take),
+        2 => Some(OpCode::ChooseFirstN),
+        3 => Some(OpCode::ChooseIndep),
+        4 => Some(OpCode::Emit),
+        6 => Some(OpCode::ChooseLeafFirstN),
+        7 => Some(OpCode::ChooseLeafIndep),
+        8 => Some(OpCode::SetChooseTries),
+        9 => Some(OpCode::SetChooseLeafTries),
+        10 => Some(OpCode::SetChooseLocalTries),
+        11 => Some(OpCode::SetChooseLocalFallbackTries),
+        12 => Some(OpCode::SetChooseLeafVaryR),
+        _ => None,
+    }
+}
+
+pub fn add_item(bucket: &mut BucketTypes,
+                item: i32,
+                name: Option<String>,
+                item_weight: u32,
+                straw_calc_version: u8)
+                -> Result<(), String> {
+    let mut items: Vec<(i32, Option<String>)> = header(bucket)
+        .map(|b| b.items.clone())
+        .unwrap_or_default();
+    let mut weights = item_weights(bucket);
+    items.push((item, name));
+    weights.push(item_weight);
+    set_items(bucket, items, weights, straw_calc_version)
+}
+
+pub fn remove_item(bucket: &mut BucketTypes,
+                   item: i32,
+                   straw_calc_version: u8)
+                   -> Result<(), String> {
+    let items: Vec<(i32, Option<String>)> = header(bucket)
+        .map(|b| b.items.clone())
+        .unwrap_or_default();
+    let weights = item_weights(bucket);
+    let mut kept_items: Vec<(i32, Option<String>)> = Vec::new();
+    let mut kept_weights: Vec<u32> = Vec::new();
+    for (entry, weight) in items.into_iter().zip(weights) {
+        if entry.0 != item {
+            kept_items.push(entry);
+            kept_weights.push(weight);
+        }
+    }
+    set_items(bucket, kept_items, kept_weights, straw_calc_version)
+}
+
+// The max_devices Ceph expects: one past the highest OSD id that is named or placed in a
+// bucket. OSD ids can be sparse, so this is not the same as the number of devices.
+pub fn device_bound(buckets: &[BucketTypes], name_map: &[(i32, String)]) -> i32 {
+    let named = name_map.iter().map(|&(id, _)| id);
+    let placed = buckets.iter()
+        .filter_map(|bucket| header(bucket))
+        .flat_map(|header| header.items.iter().map(|&(item, _)| item));
+    named.chain(placed).filter(|&id| id >= 0).max().map(|id| id + 1).unwrap_or(0)
+}
+
+// The id to give a new bucket: one below the lowest id that is named or already in use
+pub fn next_id(buckets: &[BucketTypes], name_map: &[(i32, String)]) -> i32 {
+    let named = name_map.iter().map(|&(id, _)| id);
+    let used = buckets.iter().filter_map(id);
+    named.chain(used).filter(|&id| id < 0).min().unwrap_or(0) - 1
+}
+
+// Puts a bucket into the slot Ceph expects for its id. The bucket array is indexed by -1-id
+// and any gaps are filled with empty slots.
+pub fn place(buckets: &mut Vec<BucketTypes>, bucket: BucketTypes) {
+    let slot = match id(&bucket) {
+        Some(id) => (-1 - id) as usize,
+        None => return,
+    };
+    while buckets.len() <= slot {
+        buckets.push(BucketTypes::Unknown);
+    }
+    buckets[slot] = bucket;
+}
+
+// Empties the slot holding the given bucket, leaving the other buckets where they are
+pub fn remove(buckets: &mut [BucketTypes], bucket_id: i32) {
+    for bucket in buckets.iter_mut() {
+        if id(bucket) == Some(bucket_id) {
+            *bucket = BucketTypes::Unknown;
+        }
+    }
+}
+
+// Recalculates every bucket's weight from the bottom up so that each bucket weighs the sum of
+// its children. Device weights are taken as they are.
+pub fn reweight(buckets: &mut Vec<BucketTypes>, straw_calc_version: u8) -> Result<(), String> {
+    let roots: Vec<i32> = buckets.iter()
+        .filter_map(id)
+        .filter(|&bucket_id| parent_of(buckets, bucket_id).is_none())
+        .collect();
+    for root in roots {
+        try!(reweight_bucket(buckets, root, 0, straw_calc_version));
+    }
+    Ok(())
+}
+
+fn reweight_bucket(buckets: &mut Vec<BucketTypes>,
+                   bucket_id: i32,
+                   depth: usize,
+                   straw_calc_version: u8)
+                   -> Result<u32, String> {
+    if depth > buckets.len() {
+        return Err(format!("Bucket {} is part of a loop", bucket_id));
+    }
+    let (items, mut weights) = match find(buckets, bucket_id) {
+        Some(bucket) => (header(bucket).unwrap().items.clone(), item_weights(bucket)),
+        None => return Err(format!("Bucket {} does not exist", bucket_id)),
+    };
+    for (index, &(item, _)) in items.iter().enumerate() {
+        if item < 0 {
+            weights[index] = try!(reweight_bucket(buckets, item, depth + 1, straw_calc_version));
+        }
+    }
+    let total: u32 = weights.iter().sum();
+    if let Some(bucket) = find_mut(buckets, bucket_id) {
+        if item_weights(bucket) != weights {
+            try!(set_items(bucket, items, weights, straw_calc_version));
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crushtool::{BucketAlg, BucketTypes, OpCode};
+
+    // 16.16 fixed point, so 0x10000 is a weight of 1.0
+    const ONE: u32 = 0x10000;
+
+    fn bucket_with(alg: BucketAlg, weights: Vec<u32>, straw_calc_version: u8) -> BucketTypes {
+        let mut bucket = new_bucket(-1, OpCode::Take, alg);
+        let items = (0..weights.len() as i32).map(|item| (item, None)).collect();
+        set_items(&mut bucket, items, weights, straw_calc_version).unwrap();
+        bucket
+    }
+
+    #[test]
+    fn equal_weights_get_equal_straws() {
+        assert_eq!(straw_lengths(&[ONE, ONE, ONE], 0), vec![ONE, ONE, ONE]);
+        assert_eq!(straw_lengths(&[ONE, ONE, ONE], 1), vec![ONE, ONE, ONE]);
+    }
+
+    #[test]
+    fn heavier_items_get_longer_straws() {
+        // With weights 1 and 2 the heavier item has to win two thirds of the draws. The lighter
+        // straw stays at 1.0 and the heavier one is stretched by 1 / (2/3), to 1.5.
+        assert_eq!(straw_lengths(&[2 * ONE, ONE], 1), vec![ONE * 3 / 2, ONE]);
+        // Empty items never win
+        assert_eq!(straw_lengths(&[0, ONE], 1), vec![0, ONE]);
+    }
+
+    #[test]
+    fn tree_nodes_sum_their_leaves() {
+        // Leaves sit at the odd nodes and the root of a three item tree is node 4
+        let nodes = tree_node_weights(&[1, 2, 3]).unwrap();
+        assert_eq!(nodes, vec![0, 1, 3, 2, 6, 3, 3, 0]);
+        assert_eq!(tree_node_weights(&[]), Some(vec![]));
+        assert!(tree_node_weights(&[1; 100]).is_none());
+
+        let tree = bucket_with(BucketAlg::Tree, vec![1, 2, 3], 1);
+        assert_eq!(item_weights(&tree), vec![1, 2, 3]);
+        assert_eq!(weight(&tree), 6);
+    }
+
+    #[test]
+    fn list_weights_are_cumulative() {
+        let list = bucket_with(BucketAlg::List, vec![ONE, 2 * ONE, ONE], 1);
+        match list {
+            BucketTypes::List(ref list) => {
+                assert_eq!(list.item_weights,
+                           vec![(ONE, ONE), (2 * ONE, 3 * ONE), (ONE, 4 * ONE)])
+            }
+            _ => panic!("expected a list bucket"),
+        }
+        assert_eq!(weight(&list), 4 * ONE);
+    }
+
+    #[test]
+    fn uniform_buckets_need_equal_weights() {
+        let mut uniform = new_bucket(-1, OpCode::Take, BucketAlg::Uniform);
+        assert!(set_items(&mut uniform, vec![(0, None), (1, None)], vec![ONE, ONE], 1).is_ok());
+        assert_eq!(item_weights(&uniform), vec![ONE, ONE]);
+        assert!(set_items(&mut uniform, vec![(0, None), (1, None)], vec![ONE, 2 * ONE], 1)
+            .is_err());
+    }
+
+    #[test]
+    fn adding_and_removing_items_keeps_weights() {
+        let mut straw2 = bucket_with(BucketAlg::Straw2, vec![ONE, ONE], 1);
+        add_item(&mut straw2, 5, None, 2 * ONE, 1).unwrap();
+        assert_eq!(item_weights(&straw2), vec![ONE, ONE, 2 * ONE]);
+        assert_eq!(weight(&straw2), 4 * ONE);
+        remove_item(&mut straw2, 0, 1).unwrap();
+        assert_eq!(header(&straw2).unwrap().items, vec![(1, None), (5, None)]);
+        assert_eq!(weight(&straw2), 3 * ONE);
+    }
+
+    #[test]
+    fn reweight_sums_children() {
+        let mut buckets: Vec<BucketTypes> = Vec::new();
+        let mut root = new_bucket(-1, OpCode::Take, BucketAlg::Straw2);
+        set_items(&mut root, vec![(-2, None), (-3, None)], vec![0, 0], 1).unwrap();
+        place(&mut buckets, root);
+        let mut host = new_bucket(-2, OpCode::Noop, BucketAlg::Straw);
+        set_items(&mut host, vec![(0, None), (1, None)], vec![ONE, ONE], 1).unwrap();
+        place(&mut buckets, host);
+        let mut other = new_bucket(-3, OpCode::Noop, BucketAlg::List);
+        set_items(&mut other, vec![(2, None)], vec![3 * ONE], 1).unwrap();
+        place(&mut buckets, other);
+
+        reweight(&mut buckets, 1).unwrap();
+        assert_eq!(item_weights(find(&buckets, -1).unwrap()), vec![2 * ONE, 3 * ONE]);
+        assert_eq!(weight(find(&buckets, -1).unwrap()), 5 * ONE);
+        assert_eq!(root_of(&buckets, -3), -1);
+        assert_eq!(parent_of(&buckets, 2), Some(-3));
+    }
+
+    #[test]
+    fn ids_and_slots() {
+        let mut buckets: Vec<BucketTypes> = Vec::new();
+        place(&mut buckets, bucket_with(BucketAlg::Straw2, vec![ONE], 1));
+        let mut far = new_bucket(-4, OpCode::Noop, BucketAlg::Straw2);
+        set_items(&mut far, vec![(7, None)], vec![ONE], 1).unwrap();
+        place(&mut buckets, far);
+        assert_eq!(buckets.len(), 4);
+        assert!(header(&buckets[1]).is_none());
+        assert_eq!(id(&buckets[3]), Some(-4));
+
+        let names = vec![(-6, "spare".to_string()), (9, "osd.9".to_string())];
+        assert_eq!(next_id(&buckets, &names), -7);
+        assert_eq!(device_bound(&buckets, &names), 10);
+        assert_eq!(device_bound(&buckets, &[]), 8);
+
+        remove(&mut buckets, -4);
+        assert!(find(&buckets, -4).is_none());
+        assert_eq!(buckets.len(), 4);
+    }
+
+    #[test]
+    fn most_common_alg_prefers_newer_on_ties() {
+        let buckets = vec![bucket_with(BucketAlg::Straw, vec![ONE], 1),
+                           bucket_with(BucketAlg::Straw2, vec![ONE], 1),
+                           BucketTypes::Unknown];
+        assert_eq!(most_common_alg(&buckets), Some(BucketAlg::Straw2));
+        let buckets = vec![bucket_with(BucketAlg::Straw, vec![ONE], 1),
+                           bucket_with(BucketAlg::Straw, vec![ONE], 1),
+                           bucket_with(BucketAlg::Straw2, vec![ONE], 1)];
+        assert_eq!(most_common_alg(&buckets), Some(BucketAlg::Straw));
+        assert_eq!(most_common_alg(&[]), None);
+        assert_eq!(alg_from_name("tree"), Some(BucketAlg::Tree));
+        assert_eq!(alg_from_name("rack"), None);
+    }
+}
@@ -0,0 +1,90 @@
+use juju;
+use std::env;
+use std::path::PathBuf;
+
+use state;
+
+// Each controller relation is one Ceph cluster: the dct-node application on that relation runs
+// on the cluster's OSD hosts. A cluster is named after that application, and its crushmaps are
+// kept apart from other clusters' in a directory of that name under the state directory, see
+// state.rs. The ceph-clusters config maps names to the Ceph cluster passed to
+// `ceph --cluster`, e.g. "dct-node-east=east dct-node-west=west". Clusters it doesn't list use
+// the default cluster, ceph.
+pub struct Cluster {
+    pub name: String,
+    pub relation_id: juju::Relation,
+    pub ceph_name: String,
+}
+
+impl Cluster {
+    // Where this cluster's copy of a file lives, creating the directory if needed
+    pub fn path(&self, file: &str) -> PathBuf {
+        state::directory(&self.name).join(file)
+    }
+
+    // Arguments for a ceph command run against this cluster
+    pub fn ceph_args<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+        let mut cluster_args: Vec<&str> = vec!["--cluster", &self.ceph_name];
+        cluster_args.extend_from_slice(args);
+        cluster_args
+    }
+}
+
+// Every controller relation, in relation id order
+pub fn all() -> Result<Vec<Cluster>, String> {
+    let mut relation_ids = try!(juju::relation_ids_by_identifier("controller")
+        .map_err(|e| e.to_string()));
+    relation_ids.sort_by_key(|relation| relation.id);
+    let ceph_names = juju::config_get("ceph-clusters").unwrap_or_default();
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+    for relation_id in relation_ids {
+        // Until a unit joins there is no application to name the cluster after
+        let name = match juju::relation_list_by_id(&relation_id) {
+            Ok(ref units) if !units.is_empty() => units[0].name.clone(),
+            _ => format!("{}-{}", relation_id.name, relation_id.id),
+        };
+        let ceph_name = ceph_names.split(|c: char| c == ',' || c.is_whitespace())
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key == name => Some(value.to_string()),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or("ceph".to_string());
+        clusters.push(Cluster {
+            name: name,
+            relation_id: relation_id,
+            ceph_name: ceph_name,
+        });
+    }
+    Ok(clusters)
+}
+
+// The cluster named by the action's cluster parameter. It can be left out when the controller
+// only has one.
+pub fn select() -> Result<Cluster, String> {
+    let requested = juju::action_get("cluster").unwrap_or_default().trim().to_string();
+    let mut clusters = try!(all());
+    let names: Vec<String> = clusters.iter().map(|cluster| cluster.name.clone()).collect();
+    if requested.is_empty() {
+        return match clusters.len() {
+            0 => Err("There is no controller relation".to_string()),
+            1 => Ok(clusters.remove(0)),
+            _ => Err(format!("There are several clusters, choose one of {} with cluster=",
+                             names.join(", "))),
+        };
+    }
+    match clusters.iter().position(|cluster| cluster.name == requested) {
+        Some(index) => Ok(clusters.remove(index)),
+        None => Err(format!("Unknown cluster {}, expected one of {}", requested, names.join(", "))),
+    }
+}
+
+// The unit this binary runs on, which is where the controller's settings live on each relation
+pub fn controller_unit() -> Result<juju::Relation, String> {
+    let controller_id = try!(env::var("JUJU_UNIT_NAME").map_err(|e| e.to_string()));
+    ::parse_unit_into_relation(controller_id)
+}
@@ -0,0 +1,334 @@
+use crushtool::{CrushMap, CrushRuleStep, OpCode, Rule};
+use std::collections::BTreeMap;
+
+use buckets;
+
+// Where an item sits in the hierarchy: the bucket holding it and the weight it is given there.
+// Buckets at the top of the tree have no parent and use their own weight.
+struct Placement {
+    parent: Option<String>,
+    weight: u32,
+    is_device: bool,
+}
+
+// Compares two crushmaps and describes the differences, one change per line.
+//
+// Items are matched by name rather than id, since a regenerated map gives new ids to the buckets
+// it creates. The changes are reported as added, removed and moved items, weight changes,
+// rule changes and tunable changes.
+pub fn diff_crushmaps(old_map: &CrushMap, new_map: &CrushMap) -> Vec<String> {
+    let mut changes: Vec<String> = Vec::new();
+
+    let old_placements = placements(old_map);
+    let new_placements = placements(new_map);
+
+    for (name, old) in &old_placements {
+        match new_placements.get(name) {
+            None => changes.push(format!("removed {} {}", kind(old), name)),
+            Some(new) => {
+                if old.parent != new.parent {
+                    changes.push(format!("moved {} {} from {} to {}",
+                                         kind(new),
+                                         name,
+                                         describe_parent(&old.parent),
+                                         describe_parent(&new.parent)));
+                }
+                if old.weight != new.weight {
+                    changes.push(format!("weight of {} changed from {} to {}",
+                                         name,
+                                         format_weight(old.weight),
+                                         format_weight(new.weight)));
+                }
+            }
+        }
+    }
+    for (name, new) in &new_placements {
+        if !old_placements.contains_key(name) {
+            changes.push(format!("added {} {} under {} with weight {}",
+                                 kind(new),
+                                 name,
+                                 describe_parent(&new.parent),
+                                 format_weight(new.weight)));
+        }
+    }
+
+    let old_rules = rules_by_name(old_map);
+    let new_rules = rules_by_name(new_map);
+    for (name, old) in &old_rules {
+        match new_rules.get(name) {
+            None => changes.push(format!("removed rule {}: {}", name, old)),
+            Some(new) => {
+                if old != new {
+                    changes.push(format!("changed rule {} from {} to {}", name, old, new));
+                }
+            }
+        }
+    }
+    for (name, new) in &new_rules {
+        if !old_rules.contains_key(name) {
+            changes.push(format!("added rule {}: {}", name, new));
+        }
+    }
+
+    for (tunable, old, new) in tunables(old_map, new_map) {
+        if old != new {
+            changes.push(format!("tunable {} changed from {} to {}", tunable, old, new));
+        }
+    }
+
+    changes
+}
+
+fn placements(map: &CrushMap) -> BTreeMap<String, Placement> {
+    let mut placements: BTreeMap<String, Placement> = BTreeMap::new();
+
+    for bucket in &map.buckets {
+        let header = match buckets::header(bucket) {
+            Some(header) => header,
+            None => continue,
+        };
+        let bucket_name = item_name(map, header.id);
+        for (&(item, _), weight) in header.items.iter().zip(buckets::item_weights(bucket)) {
+            placements.insert(item_name(map, item),
+                              Placement {
+                                  parent: Some(bucket_name.clone()),
+                                  weight: weight,
+                                  is_device: item >= 0,
+                              });
+        }
+    }
+    // Roots aren't held by anything, so they haven't been seen yet
+    for bucket in &map.buckets {
+        if let Some(header) = buckets::header(bucket) {
+            let name = item_name(map, header.id);
+            placements.entry(name).or_insert(Placement {
+                parent: None,
+                weight: header.weight,
+                is_device: false,
+            });
+        }
+    }
+    placements
+}
+
+fn rules_by_name(map: &CrushMap) -> BTreeMap<String, String> {
+    let mut rules: BTreeMap<String, String> = BTreeMap::new();
+    for (index, rule) in map.rules.iter().enumerate() {
+        if let Some(ref rule) = *rule {
+            let name = match map.rule_name_map.iter().find(|&&(id, _)| id == index as i32) {
+                Some((_, name)) => name.clone(),
+                None => format!("rule {}", index),
+            };
+            rules.insert(name, describe_rule(map, rule));
+        }
+    }
+    rules
+}
+
+fn describe_rule(map: &CrushMap, rule: &Rule) -> String {
+    let steps: Vec<String> = rule.steps.iter().map(|step| describe_step(map, step)).collect();
+    format!("ruleset {} {:?} size {}-{} [{}]",
+            rule.mask.ruleset,
+            rule.mask.rule_type,
+            rule.mask.min_size,
+            rule.mask.max_size,
+            steps.join(", "))
+}
+
+// Describes a rule step the way crushtool writes it, without the leading "step"
+pub fn describe_step(map: &CrushMap, step: &CrushRuleStep) -> String {
+    let type_name = |id: i32| -> String {
+        match map.type_map.iter().find(|&&(type_id, _)| type_id == id) {
+            Some((_, name)) => name.clone(),
+            None => id.to_string(),
+        }
+    };
+    match step.op {
+        OpCode::Take => format!("take {}", item_name(map, step.arg1.0)),
+        OpCode::ChooseFirstN => {
+            format!("choose firstn {} type {}", step.arg1.0, type_name(step.arg2.0))
+        }
+        OpCode::ChooseIndep => {
+            format!("choose indep {} type {}", step.arg1.0, type_name(step.arg2.0))
+        }
+        OpCode::ChooseLeafFirstN => {
+            format!("chooseleaf firstn {} type {}", step.arg1.0, type_name(step.arg2.0))
+        }
+        OpCode::ChooseLeafIndep => {
+            format!("chooseleaf indep {} type {}", step.arg1.0, type_name(step.arg2.0))
+        }
+        OpCode::Emit => "emit".to_string(),
+        OpCode::Noop => "noop".to_string(),
+        OpCode::SetChooseTries => format!("set_choose_tries {}", step.arg1.0),
+        OpCode::SetChooseLeafTries => format!("set_chooseleaf_tries {}", step.arg1.0),
+        OpCode::SetChooseLocalTries => format!("set_choose_local_tries {}", step.arg1.0),
+        OpCode::SetChooseLocalFallbackTries => {
+            format!("set_choose_local_fallback_tries {}", step.arg1.0)
+        }
+        OpCode::SetChooseLeafVaryR => format!("set_chooseleaf_vary_r {}", step.arg1.0),
+    }
+}
+
+fn tunables(old_map: &CrushMap, new_map: &CrushMap) -> Vec<(&'static str, String, String)> {
+    vec![("choose_local_tries",
+          tunable(old_map.choose_local_tries),
+          tunable(new_map.choose_local_tries)),
+         ("choose_local_fallback_tries",
+          tunable(old_map.choose_local_fallback_tries),
+          tunable(new_map.choose_local_fallback_tries)),
+         ("choose_total_tries",
+          tunable(old_map.choose_total_tries),
+          tunable(new_map.choose_total_tries)),
+         ("chooseleaf_descend_once",
+          tunable(old_map.chooseleaf_descend_once),
+          tunable(new_map.chooseleaf_descend_once)),
+         ("chooseleaf_vary_r",
+          tunable(old_map.chooseleaf_vary_r.map(|v| v as u32)),
+          tunable(new_map.chooseleaf_vary_r.map(|v| v as u32))),
+         ("straw_calc_version",
+          tunable(old_map.straw_calc_version.map(|v| v as u32)),
+          tunable(new_map.straw_calc_version.map(|v| v as u32))),
+         ("allowed_bucket_algorithms",
+          tunable(old_map.allowed_bucket_algorithms),
+          tunable(new_map.allowed_bucket_algorithms)),
+         ("chooseleaf_stable",
+          tunable(old_map.chooseleaf_stable.map(|v| v as u32)),
+          tunable(new_map.chooseleaf_stable.map(|v| v as u32)))]
+}
+
+fn tunable(value: Option<u32>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "unset".to_string(),
+    }
+}
+
+fn item_name(map: &CrushMap, id: i32) -> String {
+    match map.name_map.iter().find(|&&(index, _)| index == id) {
+        Some((_, name)) => name.clone(),
+        None if id >= 0 => format!("osd.{}", id),
+        None => format!("bucket{}", id),
+    }
+}
+
+fn kind(placement: &Placement) -> &'static str {
+    if placement.is_device { "device" } else { "bucket" }
+}
+
+fn describe_parent(parent: &Option<String>) -> String {
+    match *parent {
+        Some(ref name) => name.clone(),
+        None => "the top level".to_string(),
+    }
+}
+
+// Weights are 16.16 fixed point, shown the way Ceph prints them
+pub fn format_weight(weight: u32) -> String {
+    format!("{:.3}", weight as f64 / 65536.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use text;
+
+    const MAP: &str = "tunable straw_calc_version 1
+device 0 osd.0
+device 1 osd.1
+device 2 osd.2
+type 0 osd
+type 1 host
+type 3 rack
+type 10 root
+host node1 {
+\tid -2
+\talg straw2
+\titem osd.0 weight 1.000
+\titem osd.1 weight 1.000
+}
+host node2 {
+\tid -3
+\talg straw2
+\titem osd.2 weight 1.000
+}
+root default {
+\tid -1
+\talg straw2
+\titem node1 weight 2.000
+\titem node2 weight 1.000
+}
+rule replicated_ruleset {
+\truleset 0
+\ttype replicated
+\tmin_size 1
+\tmax_size 10
+\tstep take default
+\tstep chooseleaf firstn 0 type host
+\tstep emit
+}
+";
+
+    fn diff(new_text: &str) -> Vec<String> {
+        diff_crushmaps(&text::parse(MAP).unwrap(), &text::parse(new_text).unwrap())
+    }
+
+    #[test]
+    fn same_map_has_no_changes() {
+        assert!(diff(MAP).is_empty());
+    }
+
+    #[test]
+    fn ids_do_not_matter() {
+        // Regenerated maps renumber their buckets, items are matched by name
+        let renumbered = MAP.replace("id -2", "id -5").replace("id -3", "id -4");
+        assert!(diff(&renumbered).is_empty());
+    }
+
+    #[test]
+    fn moved_and_reweighted_items() {
+        let edited = MAP.replace("\titem osd.1 weight 1.000\n", "")
+            .replace("\titem osd.2 weight 1.000\n",
+                     "\titem osd.2 weight 1.000\n\titem osd.1 weight 0.500\n")
+            .replace("item node1 weight 2.000", "item node1 weight 1.000")
+            .replace("item node2 weight 1.000", "item node2 weight 1.500");
+        assert_eq!(diff(&edited),
+                   vec!["weight of default changed from 3.000 to 2.500",
+                        "weight of node1 changed from 2.000 to 1.000",
+                        "weight of node2 changed from 1.000 to 1.500",
+                        "moved device osd.1 from node1 to node2",
+                        "weight of osd.1 changed from 1.000 to 0.500"]);
+    }
+
+    #[test]
+    fn added_and_removed_buckets() {
+        let edited = MAP.replace("root default {",
+                                 "rack rack-a {\n\tid -4\n\talg straw2\n\titem node1 weight \
+                                  2.000\n\titem node2 weight 1.000\n}\nroot default {")
+            .replace("\titem node1 weight 2.000\n\titem node2 weight 1.000\n}\nrule",
+                     "\titem rack-a weight 3.000\n}\nrule");
+        assert_eq!(diff(&edited),
+                   vec!["moved bucket node1 from default to rack-a",
+                        "moved bucket node2 from default to rack-a",
+                        "added bucket rack-a under default with weight 3.000"]);
+        let removed = MAP.replace("\titem osd.2 weight 1.000\n", "");
+        assert!(diff(&removed).contains(&"removed device osd.2".to_string()));
+    }
+
+    #[test]
+    fn rules_and_tunables() {
+        let edited = MAP.replace("type host\n", "type rack\n")
+            .replace("tunable straw_calc_version 1", "tunable straw_calc_version 0");
+        assert_eq!(diff(&edited),
+                   vec!["changed rule replicated_ruleset from ruleset 0 Replicated size 1-10 \
+                         [take default, chooseleaf firstn 0 type host, emit] to ruleset 0 \
+                         Replicated size 1-10 [take default, chooseleaf firstn 0 type rack, emit]",
+                        "tunable straw_calc_version changed from 1 to 0"]);
+    }
+
+    #[test]
+    fn weights_print_like_ceph() {
+        assert_eq!(format_weight(0x10000), "1.000");
+        assert_eq!(format_weight(0x18000), "1.500");
+        assert_eq!(format_weight(0), "0.000");
+    }
+}
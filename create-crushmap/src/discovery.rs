@@ -0,0 +1,256 @@
+use crushtool;
+use juju;
+use log::LogLevel;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use apply;
+use cluster;
+use cluster::Cluster;
+use schedule::Schedule;
+use state;
+use status;
+
+// Starting discovery: the begin-discovery action and the scheduled rounds started from the
+// update-status hook. Each round fetches the cluster's crushmap as the baseline create-crushmap
+// builds on, then publishes a new epoch on the cluster's relation, which sends every node into
+// discovery.
+
+pub fn begin_action() {
+    let clusters = match cluster::all() {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            let _ = juju::action_fail(&format!("Could not list the controller relations: {}", e));
+            return;
+        }
+    };
+
+    // The cluster parameter picks one cluster, otherwise discovery starts on all of them
+    let requested = juju::action_get("cluster").unwrap_or_default().trim().to_string();
+    let clusters: Vec<Cluster> = clusters.into_iter()
+        .filter(|cluster| requested.is_empty() || cluster.name == requested)
+        .collect();
+    if clusters.is_empty() {
+        let _ = juju::action_fail(&format!("There is no controller relation for cluster {}",
+                                           requested));
+        return;
+    }
+
+    // Every cluster's baseline crushmap is captured before any node is told to start, so a
+    // failed fetch leaves every cluster as it was
+    let confirmed = juju::action_get("confirm")
+        .map(|confirm| confirm.trim() == "true")
+        .unwrap_or(false);
+    let mut prepared: Vec<(Cluster, Vec<juju::Relation>)> = Vec::new();
+    for cluster in clusters {
+        match prepare(&cluster, confirmed) {
+            Ok(units) => prepared.push((cluster, units)),
+            Err(e) => {
+                let _ = juju::action_fail(&format!("{}: {}", cluster.name, e));
+                return;
+            }
+        }
+    }
+
+    let mut started: Vec<String> = Vec::new();
+    for (cluster, units) in prepared {
+        match start(&cluster, &units) {
+            Ok(epoch) => {
+                let _ = juju::action_set(&format!("{}.epoch", cluster.name), &epoch.to_string());
+                started.push(format!("{} (epoch {})", cluster.name, epoch));
+            }
+            Err(e) => {
+                let _ = juju::action_fail(&format!("{}: Could not start discovery: {}",
+                                                   cluster.name,
+                                                   e));
+                return;
+            }
+        }
+    }
+
+    status::set(juju::Status {
+        status_type: juju::StatusType::Waiting,
+        message: format!("Network discovery initiated on {}", started.join(", ")),
+    });
+}
+
+// Starts a new round of discovery on every cluster whose rediscovery-interval has passed since
+// the last scheduled round. The controller compares what the round finds with the accepted
+// topology once every node has finished, see schedule.rs. Clusters that were never discovered
+// are left for the operator to start.
+pub fn scheduled_rounds() {
+    let interval = juju::config_get("rediscovery-interval")
+        .ok()
+        .and_then(|interval| interval.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    if interval == 0 {
+        return;
+    }
+    let clusters = match cluster::all() {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            juju::log(format!("Could not start scheduled discovery: {}", e),
+                      Some(LogLevel::Warn));
+            return;
+        }
+    };
+    let now = state::unix_time();
+
+    let mut started: Vec<String> = Vec::new();
+    for cluster in clusters {
+        if current_epoch(&cluster) == 0 {
+            continue;
+        }
+        let mut schedule = match Schedule::load(&cluster) {
+            Some(schedule) => schedule,
+            None => {
+                // The first check only starts the clock
+                let schedule = Schedule {
+                    epoch: 0,
+                    started: now,
+                    checked: 0,
+                };
+                let _ = schedule.save(&cluster);
+                continue;
+            }
+        };
+        if now < schedule.started + interval * 60 {
+            continue;
+        }
+
+        // Without num-units there is nothing to check the nodes against, and the operator
+        // confirmed the nodes when discovery was first started by hand
+        match prepare(&cluster, quorum().is_none()).and_then(|units| start(&cluster, &units)) {
+            Ok(epoch) => {
+                schedule.epoch = epoch;
+                schedule.started = now;
+                if let Err(e) = schedule.save(&cluster) {
+                    juju::log(format!("Could not record scheduled discovery on {}: {}",
+                                      cluster.name,
+                                      e),
+                              Some(LogLevel::Warn));
+                }
+                started.push(format!("{} (epoch {})", cluster.name, epoch));
+            }
+            Err(e) => {
+                juju::log(format!("Scheduled discovery on {} could not start: {}",
+                                  cluster.name,
+                                  e),
+                          Some(LogLevel::Warn));
+            }
+        }
+    }
+
+    if !started.is_empty() {
+        status::set(juju::Status {
+            status_type: juju::StatusType::Waiting,
+            message: format!("Scheduled network discovery initiated on {}", started.join(", ")),
+        });
+    }
+}
+
+// The number of nodes discovery waits for, from the num-units config. None when it is not set.
+fn quorum() -> Option<usize> {
+    juju::config_get("num-units")
+        .ok()
+        .and_then(|num_units| num_units.trim().parse::<usize>().ok())
+        .and_then(|quorum| if quorum == 0 { None } else { Some(quorum) })
+}
+
+// Checks a cluster is ready for discovery and captures its crushmap as the baseline
+// create-crushmap builds on. Returns the units discovery will run on.
+fn prepare(cluster: &Cluster, confirmed: bool) -> Result<Vec<juju::Relation>, String> {
+    // Discovery starts once num-units nodes are related, or earlier if the operator confirms
+    // that the nodes related so far are all there is
+    let units = juju::relation_list_by_id(&cluster.relation_id).unwrap_or_default();
+    if units.is_empty() {
+        return Err("No nodes are related to the controller yet".to_string());
+    }
+    match quorum() {
+        None if !confirmed => {
+            return Err(format!("num-units is not set, so {} related nodes may not be all of \
+                                them. Set num-units or run begin-discovery with confirm=true \
+                                once every node is related",
+                               units.len()))
+        }
+        Some(quorum) if units.len() < quorum && !confirmed => {
+            return Err(format!("Only {} of {} nodes are related. Wait for the rest or run \
+                                begin-discovery with confirm=true to start anyway",
+                               units.len(),
+                               quorum))
+        }
+        _ => {}
+    }
+
+    // The map is fetched next to the baseline and only replaces it once it decodes, so a
+    // failed fetch keeps the last good one
+    let fetched = cluster.path("currentmap.new");
+    let baseline = cluster.path("currentmap");
+    let _ = fs::remove_file(&fetched);
+    try!(fetch_crushmap(cluster, &fetched));
+    let bytes = try!(verify_crushmap(&fetched));
+    try!(fs::rename(&fetched, &baseline).map_err(|e| e.to_string()));
+    println!("Grabbed current crushmap for {}.", cluster.name);
+    if let Err(e) = state::snapshot(cluster, "baseline", &bytes) {
+        juju::log(format!("Could not save a snapshot of the baseline crushmap: {}", e),
+                  Some(LogLevel::Warn));
+    }
+
+    Ok(units)
+}
+
+// Publishes the membership and a new epoch, which sends the nodes into discovery. Returns the
+// epoch.
+fn start(cluster: &Cluster, units: &[juju::Relation]) -> Result<u64, String> {
+    let relation_id = &cluster.relation_id;
+    // Publish the membership discovery is starting with, in case it changed since the last join
+    let unit_list: Vec<String> = units.iter()
+        .map(|unit| format!("{}/{}", unit.name, unit.id))
+        .collect();
+    try!(juju::relation_set_by_id("related-units", &unit_list.join(" "), relation_id)
+        .map_err(|e| e.to_string()));
+
+    // Each run of discovery is a new epoch. Nodes rerun discovery whenever the epoch is newer
+    // than the last one they finished, and create-crushmap ignores results from older epochs.
+    let epoch = current_epoch(cluster) + 1;
+    try!(juju::relation_set_by_id("epoch", &epoch.to_string(), relation_id)
+        .map_err(|e| e.to_string()));
+    try!(juju::relation_set_by_id("ready", "1", relation_id).map_err(|e| e.to_string()));
+    Ok(epoch)
+}
+
+// The epoch this controller last published, 0 if discovery has never run
+fn current_epoch(cluster: &Cluster) -> u64 {
+    let controller = match cluster::controller_unit() {
+        Ok(controller) => controller,
+        Err(_) => return 0,
+    };
+    juju::relation_get_by_id("epoch", &cluster.relation_id, &controller)
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+// Runs `ceph osd getcrushmap`, which is killed if the cluster doesn't answer in time, see
+// apply.rs
+fn fetch_crushmap(cluster: &Cluster, path: &Path) -> Result<(), String> {
+    try!(apply::ceph(cluster, &["osd", "getcrushmap", "-o", &path.to_string_lossy()]));
+    Ok(())
+}
+
+// A baseline is only useful if crushtool can decode it and it has buckets to build on. Returns
+// the map's bytes.
+fn verify_crushmap(path: &Path) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| format!("Could not read the fetched crushmap: {}", e)));
+    let crushmap = try!(crushtool::decode_crushmap(&bytes[..])
+        .map_err(|e| format!("The fetched crushmap could not be decoded: {}", e)));
+    if crushmap.buckets.is_empty() || crushmap.name_map.is_empty() {
+        return Err("The fetched crushmap has no buckets".to_string());
+    }
+    Ok(bytes)
+}
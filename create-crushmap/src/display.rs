@@ -0,0 +1,185 @@
+use crushtool::CrushMap;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use buckets;
+use diff;
+use text;
+
+// Tree views of a crushmap for the display-crushmap action, in the spirit of `ceph osd tree`.
+// Buckets nobody holds, normally just the root, are the top of the tree. Each item shows the
+// weight its parent gives it, and top level buckets show their own weight. A bucket that shows
+// up a second time, which only a broken map with a loop can have, is listed without its children
+// so the walk ends.
+
+pub fn tree_text(map: &CrushMap) -> String {
+    let mut out = String::from("ID\tWEIGHT\tTYPE NAME\n");
+    let mut visited: HashSet<i32> = HashSet::new();
+    for (id, weight) in top_level(map) {
+        text_node(map, id, weight, 0, &mut visited, &mut out);
+    }
+    out
+}
+
+fn text_node(map: &CrushMap,
+             id: i32,
+             weight: u32,
+             depth: usize,
+             visited: &mut HashSet<i32>,
+             out: &mut String) {
+    // Like ceph osd tree, devices are shown by name alone
+    let label = match id {
+        id if id >= 0 => text::item_name(map, id),
+        id => format!("{} {}", kind(map, id), text::item_name(map, id)),
+    };
+    out.push_str(&format!("{}\t{}\t{}{}\n",
+                          id,
+                          diff::format_weight(weight),
+                          "    ".repeat(depth),
+                          label));
+    if id < 0 && !visited.insert(id) {
+        out.push_str(&format!("\t\t{}(already shown)\n", "    ".repeat(depth + 1)));
+        return;
+    }
+    for (child, child_weight) in children(map, id) {
+        text_node(map, child, child_weight, depth + 1, visited, out);
+    }
+}
+
+// The same tree as a JSON list of nodes, each with its children nested inside
+pub fn tree_json(map: &CrushMap) -> String {
+    let mut visited: HashSet<i32> = HashSet::new();
+    let nodes: Vec<String> = top_level(map)
+        .into_iter()
+        .map(|(id, weight)| json_node(map, id, weight, &mut visited))
+        .collect();
+    format!("[{}]", nodes.join(","))
+}
+
+fn json_node(map: &CrushMap, id: i32, weight: u32, visited: &mut HashSet<i32>) -> String {
+    let mut node = format!("{{\"id\":{},\"name\":{},\"type\":{},\"weight\":{}",
+                           id,
+                           json_string(&text::item_name(map, id)),
+                           json_string(&kind(map, id)),
+                           diff::format_weight(weight));
+    if id < 0 && !visited.insert(id) {
+        node.push_str(",\"repeated\":true");
+    } else if id < 0 {
+        let children: Vec<String> = children(map, id)
+            .into_iter()
+            .map(|(child, child_weight)| json_node(map, child, child_weight, visited))
+            .collect();
+        node.push_str(&format!(",\"children\":[{}]", children.join(",")));
+    }
+    node.push('}');
+    node
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Buckets without a parent, from -1 downwards
+fn top_level(map: &CrushMap) -> Vec<(i32, u32)> {
+    let mut top: Vec<(i32, u32)> = map.buckets
+        .iter()
+        .filter_map(|bucket| buckets::header(bucket))
+        .filter(|header| buckets::parent_of(&map.buckets, header.id).is_none())
+        .map(|header| (header.id, header.weight))
+        .collect();
+    top.sort_by_key(|&(id, _)| Reverse(id));
+    top
+}
+
+fn children(map: &CrushMap, id: i32) -> Vec<(i32, u32)> {
+    match buckets::find(&map.buckets, id) {
+        Some(bucket) => {
+            let items = buckets::header(bucket).unwrap().items.iter().map(|&(item, _)| item);
+            items.zip(buckets::item_weights(bucket)).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+// Devices are always OSDs, buckets are named by their type
+fn kind(map: &CrushMap, id: i32) -> String {
+    match buckets::find(&map.buckets, id).and_then(|bucket| buckets::header(bucket)) {
+        Some(header) => text::type_name(map, header.bucket_type.clone() as i32),
+        None => "osd".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "device 0 osd.0
+type 0 osd
+type 1 host
+type 10 root
+host node1 {
+\tid -2
+\talg straw2
+\titem osd.0 weight 1.000
+}
+root default {
+\tid -1
+\talg straw2
+\titem node1 weight 1.000
+}
+";
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(json_string("a\\b"), "\"a\\\\b\"");
+        assert_eq!(json_string("tab\there\n"), "\"tab\\u0009here\\u000a\"");
+    }
+
+    #[test]
+    fn names_are_escaped_in_the_tree() {
+        let mut map = text::parse(MAP).unwrap();
+        for &mut (id, ref mut name) in &mut map.name_map {
+            if id == -2 {
+                *name = "no\"de\\1".to_string();
+            }
+        }
+        assert_eq!(tree_json(&map),
+                   "[{\"id\":-1,\"name\":\"default\",\"type\":\"root\",\"weight\":1.000,\
+                    \"children\":[{\"id\":-2,\"name\":\"no\\\"de\\\\1\",\"type\":\"host\",\
+                    \"weight\":1.000,\"children\":[{\"id\":0,\"name\":\"osd.0\",\
+                    \"type\":\"osd\",\"weight\":1.000}]}]}]");
+    }
+
+    #[test]
+    fn loops_end_the_walk() {
+        let mut map = text::parse(MAP).unwrap();
+        let extra = buckets::new_bucket(-3,
+                                        buckets::type_code(1).unwrap(),
+                                        crushtool::BucketAlg::Straw2);
+        buckets::place(&mut map.buckets, extra);
+        map.name_map.push((-3, "loop".to_string()));
+        buckets::add_item(buckets::find_mut(&mut map.buckets, -2).unwrap(), -3, None, 0, 1)
+            .unwrap();
+        buckets::add_item(buckets::find_mut(&mut map.buckets, -3).unwrap(), -2, None, 0, 1)
+            .unwrap();
+
+        let tree = tree_text(&map);
+        assert_eq!(tree.matches("node1").count(), 2);
+        assert!(tree.contains("(already shown)"));
+        let json = tree_json(&map);
+        assert_eq!(json.matches("\"node1\"").count(), 2);
+        assert!(json.contains("\"repeated\":true"));
+    }
+}
@@ -0,0 +1,373 @@
+use crushtool::CrushMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use yaml_rust::{Yaml, YamlLoader};
+
+use buckets;
+use rules;
+
+// Operator knowledge that overrides what ARP discovery finds. A hints file looks like:
+//
+//   racks:
+//     rack-a: [host1, host2]
+//   separate:
+//     - [host3, host4]
+//   levels:
+//     - name: row-1
+//       type: row
+//       members: [rack-a, rack-b]
+//
+// racks pins hosts to a named rack, separate lists hosts that must never share a rack, and
+// levels adds buckets between the racks and the root. A level's members are racks or other
+// levels.
+pub struct Hints {
+    pub racks: BTreeMap<String, Vec<String>>,
+    pub separate: Vec<Vec<String>>,
+    pub levels: Vec<Level>,
+}
+
+pub struct Level {
+    pub name: String,
+    pub type_name: String,
+    pub members: Vec<String>,
+}
+
+impl Hints {
+    pub fn empty() -> Hints {
+        Hints {
+            racks: BTreeMap::new(),
+            separate: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Hints, String> {
+    let documents = try!(YamlLoader::load_from_str(text).map_err(|e| e.to_string()));
+    let document = match documents.into_iter().next() {
+        Some(document) => document,
+        None => return Ok(Hints::empty()),
+    };
+    let mut hints = Hints::empty();
+
+    if let Some(racks) = document["racks"].as_hash() {
+        for (name, members) in racks {
+            let name = try!(string(name, "rack name"));
+            hints.racks.insert(name.clone(), try!(strings(members, &name)));
+        }
+    }
+    if let Some(groups) = document["separate"].as_vec() {
+        for group in groups {
+            hints.separate.push(try!(strings(group, "separate")));
+        }
+    }
+    if let Some(levels) = document["levels"].as_vec() {
+        for level in levels {
+            let name = try!(string(&level["name"], "level name"));
+            hints.levels.push(Level {
+                type_name: try!(string(&level["type"], &format!("type of level {}", name))),
+                members: try!(strings(&level["members"], &name)),
+                name: name,
+            });
+        }
+    }
+
+    // A host can only be pinned to one rack
+    let mut pinned: HashMap<&String, &String> = HashMap::new();
+    for (rack, members) in &hints.racks {
+        for member in members {
+            if let Some(other) = pinned.insert(member, rack) {
+                return Err(format!("{} is pinned to both {} and {}", member, other, rack));
+            }
+        }
+    }
+    Ok(hints)
+}
+
+fn string(value: &Yaml, what: &str) -> Result<String, String> {
+    match *value {
+        Yaml::String(ref s) => Ok(s.clone()),
+        Yaml::Integer(i) => Ok(i.to_string()),
+        _ => Err(format!("Expected a name for {}", what)),
+    }
+}
+
+fn strings(value: &Yaml, what: &str) -> Result<Vec<String>, String> {
+    match value.as_vec() {
+        Some(items) => items.iter().map(|item| string(item, what)).collect(),
+        None => Err(format!("Expected a list of names for {}", what)),
+    }
+}
+
+// Folds the hints into the neighbor graph before racks are worked out. Pinned hosts see only
+// the other hosts pinned to the same rack, and hosts that must be kept apart stop seeing each
+// other. Pinned hosts that discovery didn't report are added.
+pub fn apply_to_neighbors(machines: &mut HashMap<String, Vec<String>>, hints: &Hints) {
+    let mut pinned_rack: HashMap<String, &Vec<String>> = HashMap::new();
+    for members in hints.racks.values() {
+        for member in members {
+            pinned_rack.insert(member.clone(), members);
+        }
+    }
+
+    for (machine, neighbors) in machines.iter_mut() {
+        if let Some(members) = pinned_rack.get(machine) {
+            *neighbors = members.iter().filter(|m| *m != machine).cloned().collect();
+            continue;
+        }
+        neighbors.retain(|neighbor| !pinned_rack.contains_key(neighbor));
+    }
+    for (member, members) in &pinned_rack {
+        if !machines.contains_key(member) {
+            machines.insert(member.clone(),
+                            members.iter().filter(|m| *m != member).cloned().collect());
+        }
+    }
+
+    for group in &hints.separate {
+        for machine in group {
+            if let Some(neighbors) = machines.get_mut(machine) {
+                neighbors.retain(|neighbor| !group.contains(neighbor));
+            }
+        }
+    }
+}
+
+// Dropping edges isn't always enough: a host that sees two hosts that must be kept apart still
+// pulls both into its rack. Such racks are split, placing each host in the first part that
+// holds nothing it must be kept apart from.
+pub fn split_racks(racks: HashSet<Vec<String>>, hints: &Hints) -> HashSet<Vec<String>> {
+    let conflicts = |a: &String, b: &String| {
+        hints.separate.iter().any(|group| group.contains(a) && group.contains(b))
+    };
+    let mut split: HashSet<Vec<String>> = HashSet::new();
+    for mut rack in racks {
+        rack.sort();
+        let mut parts: Vec<Vec<String>> = Vec::new();
+        for machine in rack {
+            match parts.iter()
+                .position(|part| !part.iter().any(|other| conflicts(&machine, other))) {
+                Some(index) => parts[index].push(machine),
+                None => parts.push(vec![machine]),
+            }
+        }
+        if parts.len() > 1 {
+            println!("Split rack into {:?} to keep separated hosts apart", parts);
+        }
+        split.extend(parts);
+    }
+    split
+}
+
+// Adds the extra levels to a map that already has its racks. Each level bucket takes the place
+// of its first member under that member's parent, and the members move into it. Levels are
+// created in dependency order so a level can hold other levels. Existing buckets with a level's
+// name are reused.
+pub fn add_levels(map: &mut CrushMap,
+                  levels: &[Level],
+                  alg: ::crushtool::BucketAlg,
+                  straw_calc_version: u8)
+                  -> Result<(), String> {
+    if levels.is_empty() {
+        return Ok(());
+    }
+    let mut pending: Vec<&Level> = levels.iter().collect();
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|level| {
+            level.members.iter().all(|member| !pending.iter().any(|other| other.name == *member))
+        });
+        let level = match ready {
+            Some(index) => pending.remove(index),
+            None => return Err("The levels in the hints contain each other in a loop".to_string()),
+        };
+        try!(add_level(map, level, alg.clone(), straw_calc_version));
+    }
+    try!(buckets::reweight(&mut map.buckets, straw_calc_version));
+    Ok(())
+}
+
+fn add_level(map: &mut CrushMap,
+             level: &Level,
+             alg: ::crushtool::BucketAlg,
+             straw_calc_version: u8)
+             -> Result<(), String> {
+    let type_id = try!(rules::type_id(&level.type_name, &map.type_map));
+    let bucket_type = try!(buckets::type_code(type_id)
+        .ok_or(format!("Level type {} cannot be encoded by crushtool", level.type_name)));
+
+    let mut members: Vec<(i32, String, u32)> = Vec::new();
+    for member in &level.members {
+        let id = match map.name_map.iter().find(|(_, name)| name == member) {
+            Some(&(id, _)) if id < 0 => id,
+            _ => return Err(format!("Level {} holds {} which is not a bucket", level.name, member)),
+        };
+        let weight = buckets::find(&map.buckets, id).map(buckets::weight).unwrap_or(0);
+        members.push((id, member.clone(), weight));
+    }
+
+    let existing = map.name_map.iter().find(|(_, name)| *name == level.name);
+    let level_id = match existing.map(|&(id, _)| id) {
+        Some(id) => id,
+        None => {
+            let id = buckets::next_id(&map.buckets, &map.name_map);
+            let parent = match members.first() {
+                Some(&(first, _, _)) => buckets::parent_of(&map.buckets, first),
+                None => None,
+            };
+            buckets::place(&mut map.buckets, buckets::new_bucket(id, bucket_type, alg));
+            map.name_map.push((id, level.name.clone()));
+            map.name_map.sort();
+            if let Some(parent) = parent {
+                if let Some(bucket) = buckets::find_mut(&mut map.buckets, parent) {
+                    try!(buckets::add_item(bucket,
+                                           id,
+                                           Some(level.name.clone()),
+                                           0,
+                                           straw_calc_version));
+                }
+            }
+            println!("Added level {} ({})", level.name, id);
+            id
+        }
+    };
+
+    for (id, name, weight) in members {
+        if buckets::parent_of(&map.buckets, id) == Some(level_id) {
+            continue;
+        }
+        if let Some(parent) = buckets::parent_of(&map.buckets, id) {
+            if let Some(bucket) = buckets::find_mut(&mut map.buckets, parent) {
+                try!(buckets::remove_item(bucket, id, straw_calc_version));
+            }
+        }
+        match buckets::find_mut(&mut map.buckets, level_id) {
+            Some(bucket) => {
+                try!(buckets::add_item(bucket, id, Some(name), weight, straw_calc_version))
+            }
+            None => return Err(format!("{} is not a bucket", level.name)),
+        }
+    }
+    map.max_buckets = map.buckets.len() as i32;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crushtool::BucketAlg;
+
+    use text;
+
+    fn neighbors(links: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        links.iter()
+            .map(|&(host, seen)| (host.to_string(), seen.iter().map(|h| h.to_string()).collect()))
+            .collect()
+    }
+
+    fn level(name: &str, members: &[&str]) -> Level {
+        Level {
+            name: name.to_string(),
+            type_name: "row".to_string(),
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    // Two racks of one host each under the default root
+    fn racked_map() -> CrushMap {
+        text::parse("device 0 osd.0\ndevice 1 osd.1\ntype 0 osd\ntype 1 host\ntype 3 rack\n\
+                     type 4 row\ntype 10 root\n\
+                     host node1 {\n\tid -2\n\talg straw2\n\titem osd.0 weight 1.000\n}\n\
+                     host node2 {\n\tid -3\n\talg straw2\n\titem osd.1 weight 2.000\n}\n\
+                     rack rack-a {\n\tid -4\n\talg straw2\n\titem node1 weight 1.000\n}\n\
+                     rack rack-b {\n\tid -5\n\talg straw2\n\titem node2 weight 2.000\n}\n\
+                     root default {\n\tid -1\n\talg straw2\n\titem rack-a weight 1.000\n\
+                     \titem rack-b weight 2.000\n}\n")
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_every_section() {
+        let hints = parse("racks:\n  rack-a: [host1, host2]\nseparate:\n  - [host3, host4]\n\
+                           levels:\n  - name: row-1\n    type: row\n    members: [rack-a]\n")
+            .unwrap();
+        assert_eq!(hints.racks["rack-a"], vec!["host1".to_string(), "host2".to_string()]);
+        assert_eq!(hints.separate, vec![vec!["host3".to_string(), "host4".to_string()]]);
+        assert_eq!(hints.levels[0].name, "row-1");
+        assert_eq!(hints.levels[0].type_name, "row");
+        assert!(parse("").unwrap().racks.is_empty());
+    }
+
+    #[test]
+    fn hosts_can_only_be_pinned_once() {
+        assert_eq!(parse("racks:\n  rack-a: [host1, host2]\n  rack-b: [host1]\n").err(),
+                   Some("host1 is pinned to both rack-a and rack-b".to_string()));
+    }
+
+    #[test]
+    fn pinned_hosts_only_see_their_rack() {
+        let mut machines = neighbors(&[("host1", &["host2", "host3"]),
+                                       ("host2", &["host1", "host3"]),
+                                       ("host3", &["host1", "host2"])]);
+        let mut hints = Hints::empty();
+        hints.racks.insert("rack-a".to_string(), vec!["host1".to_string(), "host4".to_string()]);
+        apply_to_neighbors(&mut machines, &hints);
+        assert_eq!(machines,
+                   neighbors(&[("host1", &["host4"]),
+                               ("host2", &["host3"]),
+                               ("host3", &["host2"]),
+                               ("host4", &["host1"])]));
+    }
+
+    #[test]
+    fn separated_hosts_stop_seeing_each_other() {
+        let mut machines = neighbors(&[("host1", &["host2", "host3"]),
+                                       ("host2", &["host1"]),
+                                       ("host3", &["host1"])]);
+        let mut hints = Hints::empty();
+        hints.separate.push(vec!["host1".to_string(), "host2".to_string()]);
+        apply_to_neighbors(&mut machines, &hints);
+        assert_eq!(machines,
+                   neighbors(&[("host1", &["host3"]), ("host2", &[]), ("host3", &["host1"])]));
+    }
+
+    #[test]
+    fn separate_groups_split_racks() {
+        let mut hints = Hints::empty();
+        hints.separate.push(vec!["host1".to_string(), "host3".to_string()]);
+        let rack: Vec<String> = vec!["host3".to_string(), "host2".to_string(), "host1".to_string()];
+        let split = split_racks(vec![rack].into_iter().collect(), &hints);
+        let expected: HashSet<Vec<String>> =
+            vec![vec!["host1".to_string(), "host2".to_string()], vec!["host3".to_string()]]
+                .into_iter()
+                .collect();
+        assert_eq!(split, expected);
+    }
+
+    #[test]
+    fn levels_go_between_racks_and_root() {
+        let mut map = racked_map();
+        add_levels(&mut map, &[level("row-1", &["rack-a", "rack-b"])], BucketAlg::Straw2, 1)
+            .unwrap();
+        let row = map.name_map.iter().find(|(_, name)| name == "row-1").unwrap().0;
+        assert_eq!(buckets::parent_of(&map.buckets, -4), Some(row));
+        assert_eq!(buckets::parent_of(&map.buckets, -5), Some(row));
+        assert_eq!(buckets::parent_of(&map.buckets, row), Some(-1));
+        let root = buckets::find(&map.buckets, -1).unwrap();
+        assert_eq!(buckets::item_weights(root), vec![0x30000]);
+    }
+
+    #[test]
+    fn levels_must_not_contain_each_other() {
+        let mut map = racked_map();
+        let levels = [level("row-1", &["rack-a", "row-2"]), level("row-2", &["row-1"])];
+        assert_eq!(add_levels(&mut map, &levels, BucketAlg::Straw2, 1).err(),
+                   Some("The levels in the hints contain each other in a loop".to_string()));
+    }
+
+    #[test]
+    fn levels_must_hold_buckets() {
+        let mut map = racked_map();
+        let levels = [level("row-1", &["rack-a", "rack-z"])];
+        assert_eq!(add_levels(&mut map, &levels, BucketAlg::Straw2, 1).err(),
+                   Some("Level row-1 holds rack-z which is not a bucket".to_string()));
+    }
+}
@@ -0,0 +1,289 @@
+use crushtool::{BucketAlg, BucketTypes, CrushMap, OpCode};
+use juju;
+use log::LogLevel;
+use std::collections::BTreeMap;
+
+use buckets;
+use rules;
+
+// Incremental mode works on the decoded current map instead of rebuilding it from scratch.
+//
+// Each discovered rack gets a new bucket which is slotted in between the hosts and whatever
+// bucket held them before, usually the default root. Everything else in the map is left as it
+// was: other roots, rules, the type list and tunables all carry over, so pools keep pointing at
+// valid rulesets. Hosts that were racked by an earlier run are moved out of their old rack, and
+// racks left empty by that are removed. A rack that already exists under the same name is reused
+// in place, so rerunning with the same racks leaves the map as it was. New racks use the given
+// bucket algorithm and existing buckets keep theirs. Returns the updated map along with the root
+// the new racks hang from, so rules can be generated against it.
+pub fn insert_racks(mut map: CrushMap,
+                    racks: BTreeMap<String, Vec<String>>,
+                    alg: BucketAlg)
+                    -> Result<(CrushMap, i32), String> {
+    let straw_calc_version = map.straw_calc_version.unwrap_or(0);
+    // Racks are whatever type the map's own type list calls rack
+    let rack_type_id = try!(rules::type_id("rack", &map.type_map));
+    let rack_type = try!(buckets::type_code(rack_type_id)
+        .ok_or("The rack type cannot be encoded by crushtool".to_string()));
+
+    let mut current_index: i32 = buckets::next_id(&map.buckets, &map.name_map);
+
+    let mut rack_root: Option<i32> = None;
+    let mut old_racks: Vec<i32> = Vec::new();
+
+    for (name, members) in racks {
+        let existing_rack = match map.name_map.iter().find(|(_, known)| *known == name) {
+            Some(&(index, _)) if is_rack(&map.buckets, &rack_type, index) => Some(index),
+            Some(&(index, _)) => {
+                return Err(format!("{} is already used by bucket {}, which is not a rack",
+                                   name,
+                                   index))
+            }
+            None => None,
+        };
+
+        let mut rack_items: Vec<(i32, Option<String>)> = Vec::new();
+        let mut rack_weights: Vec<u32> = Vec::new();
+        let mut rack_parent: Option<i32> = None;
+
+        for machine in &members {
+            let host_id = match map.name_map.iter().find(|(_, name)| name == machine) {
+                Some(&(index, _)) => index,
+                None => return Err(format!("Could not find {} in the current crushmap", machine)),
+            };
+            let host_weight = match buckets::find(&map.buckets, host_id) {
+                Some(bucket) => buckets::weight(bucket),
+                None => return Err(format!("Could not find the bucket for {}", machine)),
+            };
+
+            let old_parent = buckets::parent_of(&map.buckets, host_id);
+            let mut parent = old_parent;
+            // Hosts racked by an earlier run are pulled out of their old rack and the new rack
+            // goes where the old one was.
+            if let Some(old_rack) = old_parent {
+                if is_rack(&map.buckets, &rack_type, old_rack) {
+                    old_racks.push(old_rack);
+                    parent = buckets::parent_of(&map.buckets, old_rack);
+                }
+            }
+            if let Some(old_parent) = old_parent {
+                if let Some(bucket) = buckets::find_mut(&mut map.buckets, old_parent) {
+                    try!(buckets::remove_item(bucket, host_id, straw_calc_version));
+                }
+            }
+
+            match (rack_parent, parent) {
+                (None, Some(parent)) => rack_parent = Some(parent),
+                (Some(existing), Some(parent)) if existing != parent => {
+                    juju::log(format!("{} was under bucket {} but its rack is placed under {}",
+                                      machine,
+                                      parent,
+                                      existing),
+                              Some(LogLevel::Warn));
+                }
+                _ => {}
+            }
+            rack_items.push((host_id, Some(machine.clone())));
+            rack_weights.push(host_weight);
+        }
+
+        // The rack is already there from an earlier run, so its hosts go back into it and it
+        // stays where it is
+        if let Some(rack_id) = existing_rack {
+            match buckets::find_mut(&mut map.buckets, rack_id) {
+                Some(rack) => {
+                    for ((host_id, host_name), weight) in rack_items.into_iter()
+                        .zip(rack_weights) {
+                        try!(buckets::add_item(rack,
+                                               host_id,
+                                               host_name,
+                                               weight,
+                                               straw_calc_version));
+                    }
+                }
+                None => return Err(format!("Could not find the bucket for {}", name)),
+            }
+            println!("Updated {} ({})", name, rack_id);
+            rack_root = Some(buckets::root_of(&map.buckets, rack_id));
+            continue;
+        }
+
+        let parent = match rack_parent {
+            Some(parent) => parent,
+            None => return Err(format!("None of {:?} are attached to a root bucket", members)),
+        };
+
+        let mut rack = buckets::new_bucket(current_index, rack_type.clone(), alg.clone());
+        try!(buckets::set_items(&mut rack, rack_items, rack_weights, straw_calc_version));
+        let rack_weight = buckets::weight(&rack);
+        buckets::place(&mut map.buckets, rack);
+
+        match buckets::find_mut(&mut map.buckets, parent) {
+            Some(bucket) => {
+                try!(buckets::add_item(bucket,
+                                       current_index,
+                                       Some(name.clone()),
+                                       rack_weight,
+                                       straw_calc_version))
+            }
+            None => return Err(format!("Could not find parent bucket {}", parent)),
+        }
+        println!("Inserted {} ({}) under bucket {}", name, current_index, parent);
+        map.name_map.push((current_index, name));
+        rack_root = Some(buckets::root_of(&map.buckets, parent));
+        current_index -= 1;
+    }
+
+    // Racks from an earlier run that no longer hold anything are dropped
+    old_racks.sort();
+    old_racks.dedup();
+    for old_rack in old_racks {
+        let is_empty = match buckets::find(&map.buckets, old_rack).and_then(buckets::header) {
+            Some(header) => header.items.is_empty(),
+            None => false,
+        };
+        if !is_empty {
+            continue;
+        }
+        if let Some(parent) = buckets::parent_of(&map.buckets, old_rack) {
+            if let Some(bucket) = buckets::find_mut(&mut map.buckets, parent) {
+                try!(buckets::remove_item(bucket, old_rack, straw_calc_version));
+            }
+        }
+        buckets::remove(&mut map.buckets, old_rack);
+        map.name_map.retain(|&(index, _)| index != old_rack);
+        println!("Removed empty rack {}", old_rack);
+    }
+
+    try!(buckets::reweight(&mut map.buckets, straw_calc_version));
+    map.name_map.sort();
+    map.max_buckets = map.buckets.len() as i32;
+
+    match rack_root {
+        Some(root) => Ok((map, root)),
+        None => Err("No racks were inserted into the current crushmap.".to_string()),
+    }
+}
+
+fn is_rack(all_buckets: &[BucketTypes], rack_type: &OpCode, id: i32) -> bool {
+    match buckets::find(all_buckets, id).and_then(buckets::header) {
+        Some(header) => header.bucket_type == *rack_type,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crushtool::{BucketAlg, CrushMap};
+    use std::collections::BTreeMap;
+
+    use text;
+
+    // Racks are type 2 here rather than Ceph's usual 3
+    const TYPES: &str = "device 0 osd.0
+device 1 osd.1
+device 2 osd.2
+type 0 osd
+type 1 host
+type 2 rack
+type 10 root
+host node1 {
+\tid -2
+\talg straw2
+\titem osd.0 weight 1.000
+}
+host node2 {
+\tid -3
+\talg straw2
+\titem osd.1 weight 1.000
+}
+host node3 {
+\tid -4
+\talg straw2
+\titem osd.2 weight 2.000
+}
+";
+
+    fn map(rest: &str) -> CrushMap {
+        text::parse(&format!("{}{}", TYPES, rest)).unwrap()
+    }
+
+    fn racks(racks: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        racks.iter()
+            .map(|&(rack, hosts)| (rack.to_string(), hosts.iter().map(|h| h.to_string()).collect()))
+            .collect()
+    }
+
+    fn id(map: &CrushMap, name: &str) -> Option<i32> {
+        map.name_map.iter().find(|(_, known)| known == name).map(|&(id, _)| id)
+    }
+
+    fn items(map: &CrushMap, name: &str) -> Vec<(i32, u32)> {
+        let bucket = buckets::find(&map.buckets, id(map, name).unwrap()).unwrap();
+        let ids = buckets::header(bucket).unwrap().items.iter().map(|&(id, _)| id);
+        ids.zip(buckets::item_weights(bucket)).collect()
+    }
+
+    #[test]
+    fn racks_go_between_hosts_and_root() {
+        let flat = map("root default {\n\tid -1\n\talg straw2\n\titem node1 weight 1.000\n\
+                        \titem node2 weight 1.000\n\titem node3 weight 2.000\n}\n");
+        let (map, root) = insert_racks(flat, racks(&[("rack-a", &["node1", "node2"])]),
+                                       BucketAlg::Straw2)
+            .unwrap();
+        assert_eq!(root, -1);
+        let rack = id(&map, "rack-a").unwrap();
+        assert_eq!(rack, -5);
+        assert!(is_rack(&map.buckets, &OpCode::ChooseFirstN, rack));
+        assert_eq!(items(&map, "rack-a"), vec![(-2, 0x10000), (-3, 0x10000)]);
+        assert_eq!(items(&map, "default"), vec![(-4, 0x20000), (rack, 0x20000)]);
+    }
+
+    #[test]
+    fn existing_racks_are_reused() {
+        let racked = map("rack rack-a {\n\tid -5\n\talg straw2\n\titem node1 weight 1.000\n}\n\
+                          root default {\n\tid -1\n\talg straw2\n\titem rack-a weight 1.000\n\
+                          \titem node2 weight 1.000\n\titem node3 weight 2.000\n}\n");
+        let (map, _) = insert_racks(racked, racks(&[("rack-a", &["node1", "node2"])]),
+                                    BucketAlg::Straw2)
+            .unwrap();
+        assert_eq!(id(&map, "rack-a"), Some(-5));
+        assert_eq!(map.buckets.iter().filter_map(buckets::id).count(), 5);
+        assert_eq!(items(&map, "rack-a"), vec![(-2, 0x10000), (-3, 0x10000)]);
+        // The root is reweighted for the host that moved under the rack
+        assert_eq!(items(&map, "default"), vec![(-5, 0x20000), (-4, 0x20000)]);
+    }
+
+    #[test]
+    fn emptied_racks_are_removed() {
+        let racked = map("rack rack-old {\n\tid -5\n\talg straw2\n\titem node1 weight 1.000\n\
+                          \titem node2 weight 1.000\n}\n\
+                          root default {\n\tid -1\n\talg straw2\n\titem rack-old weight 2.000\n\
+                          \titem node3 weight 2.000\n}\n");
+        let (map, root) = insert_racks(racked,
+                                       racks(&[("rack-b", &["node1", "node2"]),
+                                               ("rack-c", &["node3"])]),
+                                       BucketAlg::Straw2)
+            .unwrap();
+        assert_eq!(root, -1);
+        assert_eq!(id(&map, "rack-old"), None);
+        assert!(buckets::find(&map.buckets, -5).is_none());
+        let (b, c) = (id(&map, "rack-b").unwrap(), id(&map, "rack-c").unwrap());
+        assert_eq!(items(&map, "default"), vec![(b, 0x20000), (c, 0x20000)]);
+    }
+
+    #[test]
+    fn names_of_other_buckets_are_not_taken() {
+        let flat = map("root default {\n\tid -1\n\talg straw2\n\titem node1 weight 1.000\n}\n");
+        assert!(insert_racks(flat, racks(&[("node2", &["node1"])]), BucketAlg::Straw2).is_err());
+    }
+
+    #[test]
+    fn maps_without_a_rack_type_are_refused() {
+        let mut flat = map("root default {\n\tid -1\n\talg straw2\n\titem node1 weight 1.000\n}\n");
+        flat.type_map.retain(|(_, name)| name != "rack");
+        assert_eq!(insert_racks(flat, racks(&[("rack-a", &["node1"])]), BucketAlg::Straw2).err(),
+                   Some("Unknown bucket type: rack".to_string()));
+    }
+}
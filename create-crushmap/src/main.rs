@@ -1,107 +1,858 @@
 extern crate crushtool;
 extern crate juju;
 extern crate log;
+extern crate yaml_rust;
 
 use log::LogLevel;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::io::prelude::*;
 use std::fs::File;
+use std::str::FromStr;
+
+mod apply;
+mod buckets;
+mod cluster;
+mod diff;
+mod discovery;
+mod display;
+mod hints;
+mod incremental;
+mod membership;
+mod naming;
+mod progress;
+mod rules;
+mod schedule;
+mod simulate;
+mod state;
+mod status;
+mod text;
+mod topology;
+mod units;
+mod validate;
 
 // Here is where the controller takes input from the subordinate services,
 // determines which nodes are in the same rack, and finally
 // creates the crushmap from those clusters.
 //
 
+// Settings for the crushmap, read from the charm config
+struct CrushmapOptions {
+    // Bucket type the generated rules spread replicas across
+    failure_domain: String,
+    // Also generate an erasure coded rule
+    erasure_coded: bool,
+    // Insert racks into the current map rather than replacing it
+    incremental: bool,
+    // Algorithm for the buckets we create, None to match the existing buckets
+    bucket_algorithm: Option<crushtool::BucketAlg>,
+    // How racks are named, see naming.rs
+    rack_name_template: String,
+    // Operator overrides for rack membership and extra levels, see hints.rs
+    hints: hints::Hints,
+}
+
+// Generated maps use the corrected straw length calculation
+const STRAW_CALC_VERSION: u8 = 1;
+
 
 fn main() {
+    // The controller relation hooks run this binary too, to keep the membership and discovery
+    // progress up to date, and update-status starts scheduled rounds of discovery
+    if let Ok(hook) = env::var("JUJU_HOOK_NAME") {
+        if hook.starts_with("controller-relation-") {
+            return progress_hook(&hook);
+        }
+        if hook == "update-status" {
+            return discovery::scheduled_rounds();
+        }
+    }
+    // The same binary serves several actions, told apart by the name Juju runs it under
+    match juju::action_name() {
+        Ok(ref action) if action == "begin-discovery" => discovery::begin_action(),
+        Ok(ref action) if action == "discovery-status" => discovery_status_action(),
+        Ok(ref action) if action == "diff-crushmap" => diff_action(),
+        Ok(ref action) if action == "apply-crushmap" => apply_action(),
+        Ok(ref action) if action == "display-crushmap" => display_action(),
+        Ok(ref action) if action == "add-units" => units_action(true),
+        Ok(ref action) if action == "remove-units" => units_action(false),
+        Ok(ref action) if action == "topology-history" => topology_history_action(),
+        Ok(ref action) if action == "accept-topology" => accept_topology_action(),
+        _ => create_action(),
+    }
+}
 
-    let machines = grab_relation_data();
-    let racks = generate_racks(machines);
+fn create_action() {
 
-    let crush_result = match generate_crushmap(racks) {
-        Ok(_) => {
-            let _ = juju::status_set(juju::Status {
-                status_type: juju::StatusType::Maintenance,
-                message: "Crushmap generated in /tmp. Please examine crushmap with Ceph before \
-                          use."
-                    .to_string(),
+    let options = match crushmap_options() {
+        Ok(options) => options,
+        Err(e) => {
+            let message = format!("Failed to load rack hints with error: {}", e);
+            juju::log(&message, Some(LogLevel::Error));
+            let _ = juju::action_fail(&message);
+            return;
+        }
+    };
+    let cluster = match cluster::select() {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            let _ = juju::action_fail(&e);
+            return;
+        }
+    };
+
+    let crush_result = discover_racks(&cluster, &options)
+        .and_then(|(named_racks, discovered)| {
+            record_topology(&cluster, &topology::Topology::new(&named_racks, &discovered));
+            generate_crushmap(&cluster, named_racks, &options)
+        })
+        .and_then(|map| write_crushmap(&cluster, map));
+    juju::log(format!("{:?}", crush_result), Some(LogLevel::Info));
+    println!("{:?}", crush_result);
+    if let Err(e) = crush_result {
+        // Whatever dct_crushmap holds is left over from an earlier run, so stop here rather
+        // than diff it against the cluster
+        let message = format!("Failed to create crushmap with error: {}", e);
+        juju::log(&message, Some(LogLevel::Error));
+        status::set(juju::Status {
+            status_type: juju::StatusType::Maintenance,
+            message: message.clone(),
+        });
+        let _ = juju::action_fail(&message);
+        return;
+    }
+    status::set(juju::Status {
+        status_type: juju::StatusType::Maintenance,
+        message: format!("Crushmap for {} generated in {}. Please examine crushmap with Ceph \
+                          before use.",
+                         cluster.name,
+                         cluster.path("").display()),
+    });
+
+    match diff_against_current(&cluster) {
+        Ok(changes) => report_changes(&changes),
+        Err(e) => juju::log(format!("Could not compare crushmaps: {}", e), Some(LogLevel::Warn)),
+    }
+    match estimate_movement(&cluster) {
+        Ok(movement) => report_movement(&movement),
+        Err(e) => {
+            juju::log(format!("Could not estimate data movement: {}", e),
+                      Some(LogLevel::Warn))
+        }
+    }
+}
+
+// Settings for the crushmap from the charm config, with the operator's rack hints
+fn crushmap_options() -> Result<CrushmapOptions, String> {
+    let hints = try!(load_hints());
+    Ok(CrushmapOptions {
+        failure_domain: match juju::config_get("failure-domain") {
+            Ok(ref domain) if !domain.is_empty() => domain.clone(),
+            _ => "rack".to_string(),
+        },
+        erasure_coded: config_flag("erasure-coded-rules"),
+        incremental: config_flag("incremental"),
+        bucket_algorithm: match juju::config_get("bucket-algorithm") {
+            Ok(ref alg) if !alg.is_empty() && alg != "auto" => {
+                let parsed = buckets::alg_from_name(alg);
+                if parsed.is_none() {
+                    juju::log(format!("Unknown bucket-algorithm {}, matching the existing \
+                                       buckets instead",
+                                      alg),
+                              Some(LogLevel::Warn));
+                }
+                parsed
+            }
+            _ => None,
+        },
+        rack_name_template: match juju::config_get("rack-name-template") {
+            Ok(ref template) if !template.is_empty() => template.clone(),
+            _ => "rack-{first}".to_string(),
+        },
+        hints: hints,
+    })
+}
+
+// Rack names with their hosts, and each host's neighbors
+type RacksAndNeighbors = (BTreeMap<String, Vec<String>>, HashMap<String, Vec<String>>);
+
+// Reads the nodes' neighbors and works out the named racks. Returns the racks along with the
+// neighbors as discovered, before any hints are applied.
+fn discover_racks(cluster: &cluster::Cluster,
+                  options: &CrushmapOptions)
+                  -> Result<RacksAndNeighbors, String> {
+    let mut machines = try!(grab_relation_data(cluster));
+    let neighbors = state::neighbors_text(&machines);
+    if let Err(e) = state::snapshot(cluster, "neighbors", neighbors.as_bytes()) {
+        juju::log(format!("Could not save the neighbor data: {}", e), Some(LogLevel::Warn));
+    }
+    let discovered = machines.clone();
+    hints::apply_to_neighbors(&mut machines, &options.hints);
+    let racks = hints::split_racks(generate_racks(machines), &options.hints);
+    let named_racks = try!(naming::name_racks(racks,
+                                              &options.rack_name_template,
+                                              &options.hints.racks));
+    Ok((named_racks, discovered))
+}
+
+fn diff_action() {
+    let result = cluster::select().and_then(|cluster| {
+        try!(load_text_map(&cluster));
+        report_changes(&try!(diff_against_current(&cluster)));
+        estimate_movement(&cluster)
+    });
+    match result {
+        Ok(movement) => report_movement(&movement),
+        Err(e) => {
+            let message = format!("Failed to compare crushmaps with error: {}", e);
+            juju::log(&message, Some(LogLevel::Error));
+            let _ = juju::action_fail(&message);
+        }
+    }
+}
+
+fn apply_action() {
+    let options = apply::ApplyOptions {
+        watch_window: config_number("health-watch-window", 300),
+        check_interval: config_number("health-check-interval", 15),
+    };
+    let result = cluster::select().and_then(|cluster| {
+        try!(load_text_map(&cluster));
+        let new_map = try!(read_crushmap(&cluster, "dct_crushmap"));
+        let backup = try!(apply::apply_crushmap(&cluster, &new_map[..], &options));
+        Ok((cluster, backup))
+    });
+    match result {
+        Ok((cluster, backup)) => {
+            // The applied map was built from the latest topology, so drift is measured from it
+            match topology::history(&cluster).map(|mut history| history.pop()) {
+                Ok(Some((_, latest))) => {
+                    if let Err(e) = topology::accept(&cluster, &latest) {
+                        juju::log(format!("Could not accept the topology: {}", e),
+                                  Some(LogLevel::Warn));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    juju::log(format!("Could not read the topology history: {}", e),
+                              Some(LogLevel::Warn))
+                }
+            }
+            let message = format!("Crushmap applied. The previous map was saved to {}",
+                                  backup.display());
+            juju::log(&message, Some(LogLevel::Info));
+            let _ = juju::action_set("backup", &backup.to_string_lossy());
+            status::set(juju::Status {
+                status_type: juju::StatusType::Active,
+                message: "Crushmap applied".to_string(),
             });
         }
         Err(e) => {
-            let message = format!("Failed to create crushmap with error: {}", e);
+            let message = format!("Failed to apply crushmap with error: {}", e);
             juju::log(&message, Some(LogLevel::Error));
-            let _ = juju::status_set(juju::Status {
+            let _ = juju::action_fail(&message);
+        }
+    }
+}
+
+// Nodes report on the relation as they work through discovery, so each change updates the
+// controller's status with how many have finished. Departures change the count too.
+fn progress_hook(hook: &str) {
+    if hook == "controller-relation-joined" || hook == "controller-relation-departed" {
+        membership::publish(hook);
+    }
+    match collect_progress(false) {
+        Ok(mut clusters) => {
+            // A departing unit can still be listed while its departed hook runs
+            if let Ok(departing) = env::var("JUJU_REMOTE_UNIT") {
+                if hook == "controller-relation-departed" {
+                    for &mut (_, ref mut progress) in clusters.iter_mut() {
+                        progress.units.retain(|unit| unit.unit != departing);
+                    }
+                }
+            }
+            for (name, progress) in &clusters {
+                juju::log(format!("{}: {}", name, progress.summary()), Some(LogLevel::Info));
+            }
+            // Only a finished round can need comparing, see schedule.rs
+            if clusters.iter().any(|(_, progress)| progress.epoch != 0 && progress.is_complete()) {
+                check_scheduled_rounds(&clusters);
+            }
+            progress::set_status(&clusters);
+        }
+        Err(e) => juju::log(format!("Could not check discovery progress: {}", e),
+                            Some(LogLevel::Warn)),
+    }
+}
+
+// Rounds of discovery started on schedule, see discovery.rs, are compared with the accepted
+// topology once every node has finished. Drift is flagged for the operator to look into and
+// keeps the status blocked until a topology is accepted; nothing is generated or applied.
+fn check_scheduled_rounds(progress: &[(String, progress::Progress)]) {
+    let clusters = match cluster::all() {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            juju::log(format!("Could not check scheduled discovery: {}", e),
+                      Some(LogLevel::Warn));
+            return;
+        }
+    };
+    for cluster in clusters {
+        let mut schedule = match schedule::Schedule::load(&cluster) {
+            Some(schedule) => schedule,
+            None => continue,
+        };
+        let due = progress.iter().any(|(name, progress)| {
+            *name == cluster.name && schedule.needs_check(progress.epoch, progress.is_complete())
+        });
+        if !due {
+            continue;
+        }
+        // The round is marked as checked before it is compared, so a comparison that fails, or
+        // a schedule that can't be saved, doesn't record another topology on every hook
+        schedule.checked = schedule.epoch;
+        if let Err(e) = schedule.save(&cluster) {
+            juju::log(format!("Could not save the discovery schedule, not comparing round {} \
+                               of {}: {}",
+                              schedule.epoch,
+                              cluster.name,
+                              e),
+                      Some(LogLevel::Warn));
+            continue;
+        }
+        match scheduled_drift(&cluster) {
+            Ok(ref changes) if changes.is_empty() => {
+                juju::log(format!("Scheduled discovery on {} found no topology drift",
+                                  cluster.name),
+                          Some(LogLevel::Info));
+            }
+            Ok(changes) => {
+                juju::log(format!("Scheduled discovery on {} found topology drift: {}",
+                                  cluster.name,
+                                  changes.join("; ")),
+                          Some(LogLevel::Warn));
+                if let Err(e) = topology::flag_drift(&cluster, &changes) {
+                    juju::log(format!("Could not flag the topology drift: {}", e),
+                              Some(LogLevel::Warn));
+                }
+            }
+            Err(e) => {
+                juju::log(format!("Could not compare the topology of {}: {}", cluster.name, e),
+                          Some(LogLevel::Warn));
+            }
+        }
+    }
+}
+
+// Records the topology the latest round found and describes how it differs from the accepted
+// one. When nothing has been accepted yet it is compared with the last recorded topology, and
+// the first topology ever recorded becomes the accepted one.
+fn scheduled_drift(cluster: &cluster::Cluster) -> Result<Vec<String>, String> {
+    let options = try!(crushmap_options());
+    let (named_racks, discovered) = try!(discover_racks(cluster, &options));
+    let found = topology::Topology::new(&named_racks, &discovered);
+    let accepted = match try!(topology::accepted(cluster)) {
+        Some(accepted) => Some(accepted),
+        None => try!(topology::history(cluster)).pop().map(|(_, topology)| topology),
+    };
+    try!(topology::save(cluster, &found));
+    match accepted {
+        Some(accepted) => Ok(topology::compare(&accepted, &found).describe()),
+        None => {
+            try!(topology::accept(cluster, &found));
+            Ok(Vec::new())
+        }
+    }
+}
+
+// Marks the latest recorded topology as the accepted one, so scheduled discovery stops
+// reporting drift the operator has reviewed
+fn accept_topology_action() {
+    let result = cluster::select().and_then(|cluster| {
+        let (time, latest) = match try!(topology::history(&cluster)).pop() {
+            Some(latest) => latest,
+            None => return Err("No topology has been recorded yet".to_string()),
+        };
+        try!(topology::accept(&cluster, &latest));
+        Ok((cluster, time, latest))
+    });
+    match result {
+        Ok((cluster, time, latest)) => {
+            let message = format!("Accepted the topology of {} from {}: {}",
+                                  cluster.name,
+                                  time,
+                                  latest.summary());
+            juju::log(&message, Some(LogLevel::Info));
+            let _ = juju::action_set("accepted", &message);
+            status::set(juju::Status {
+                status_type: juju::StatusType::Active,
+                message: format!("Topology of {} accepted", cluster.name),
+            });
+        }
+        Err(e) => {
+            let _ = juju::action_fail(&format!("Could not accept the topology: {}", e));
+        }
+    }
+}
+
+// Returns the state of every node in the current round of discovery, for the cluster named by
+// the cluster parameter or for all of them
+fn discovery_status_action() {
+    let clusters = match collect_progress(true) {
+        Ok(clusters) => clusters,
+        Err(e) => {
+            let _ = juju::action_fail(&format!("Could not check discovery progress: {}", e));
+            return;
+        }
+    };
+    for (name, progress) in &clusters {
+        println!("{}: {}\n{}", name, progress.summary(), progress.table());
+        let _ = juju::action_set(&format!("{}.epoch", name), &progress.epoch.to_string());
+        let _ = juju::action_set(&format!("{}.summary", name), &progress.summary());
+        let _ = juju::action_set(&format!("{}.table", name), &progress.table());
+        for unit in &progress.units {
+            // Action keys can't contain slashes
+            let key = format!("{}.units.{}", name, unit.unit.replace("/", "-"));
+            let (state, reason) = match unit.state {
+                progress::UnitState::Pending => ("pending", ""),
+                progress::UnitState::Running => ("running", ""),
+                progress::UnitState::Finished => ("finished", ""),
+                progress::UnitState::Failed(ref reason) => ("failed", reason.as_str()),
+            };
+            let _ = juju::action_set(&format!("{}.hostname", key), &unit.hostname);
+            let _ = juju::action_set(&format!("{}.state", key), state);
+            if !reason.is_empty() {
+                let _ = juju::action_set(&format!("{}.reason", key), reason);
+            }
+        }
+    }
+    progress::set_status(&clusters);
+}
+
+// Discovery progress for every cluster, or only the one the action names when selected is set
+// and the cluster parameter is given
+fn collect_progress(selected: bool) -> Result<Vec<(String, progress::Progress)>, String> {
+    let requested = juju::action_get("cluster").unwrap_or_default();
+    let clusters = if selected && !requested.trim().is_empty() {
+        vec![try!(cluster::select())]
+    } else {
+        try!(cluster::all())
+    };
+    let controller = try!(cluster::controller_unit());
+    let mut progress: Vec<(String, progress::Progress)> = Vec::new();
+    for cluster in clusters {
+        let cluster_progress = try!(progress::collect(&cluster.relation_id, &controller));
+        progress.push((cluster.name, cluster_progress));
+    }
+    Ok(progress)
+}
+
+// Shows the live cluster map, the generated map or both as a tree, in text and JSON. The
+// source parameter picks which: current, generated or both.
+fn display_action() {
+    let cluster = match cluster::select() {
+        Ok(cluster) => cluster,
+        Err(e) => {
+            let _ = juju::action_fail(&e);
+            return;
+        }
+    };
+    let source = juju::action_get("source").unwrap_or_default();
+    let source = match source.trim() {
+        "" => "both",
+        source => source,
+    };
+    let mut maps: Vec<(&str, Result<crushtool::CrushMap, String>)> = Vec::new();
+    if source == "current" || source == "both" {
+        maps.push(("current", live_crushmap(&cluster)));
+    }
+    if source == "generated" || source == "both" {
+        maps.push(("generated",
+                   read_crushmap(&cluster, "dct_crushmap")
+                       .and_then(|bytes| crushtool::decode_crushmap(&bytes[..]))));
+    }
+    if maps.is_empty() {
+        let _ = juju::action_fail(&format!("Unknown source {}, expected current, generated or \
+                                            both",
+                                           source));
+        return;
+    }
+
+    let mut failures: Vec<String> = Vec::new();
+    for (name, map) in maps {
+        match map {
+            Ok(map) => {
+                let tree = display::tree_text(&map);
+                println!("{} crushmap:\n{}", name, tree);
+                let _ = juju::action_set(&format!("{}.tree", name), &tree);
+                let _ = juju::action_set(&format!("{}.json", name), &display::tree_json(&map));
+            }
+            Err(e) => failures.push(format!("Could not read the {} crushmap: {}", name, e)),
+        }
+    }
+    // Showing one map is still useful when the other is missing, so only fail when neither
+    // could be read
+    if !failures.is_empty() {
+        let message = failures.join("; ");
+        juju::log(&message, Some(LogLevel::Warn));
+        if failures.len() == 2 || source != "both" {
+            let _ = juju::action_fail(&message);
+        }
+    }
+}
+
+// Fetches the map the cluster is running right now, rather than the copy begin-discovery saved
+fn live_crushmap(cluster: &cluster::Cluster) -> Result<crushtool::CrushMap, String> {
+    let path = cluster.path("dct_livemap");
+    try!(apply::ceph(cluster, &["osd", "getcrushmap", "-o", &path.to_string_lossy()]));
+    crushtool::decode_crushmap(&try!(read_crushmap(cluster, "dct_livemap"))[..])
+}
+
+// Adds hosts to, or removes them from, the last recorded topology and generates the map again
+// from it. The name parameter can list several hosts separated by spaces or commas. The edited
+// topology is saved as a new snapshot and the map replaces the cluster's dct_crushmap, so
+// diff-crushmap and apply-crushmap pick it up without discovery running again.
+fn units_action(adding: bool) {
+    let result = cluster::select().and_then(|cluster| {
+        try!(edit_units(&cluster, adding));
+        Ok((try!(diff_against_current(&cluster)), cluster))
+    });
+    match result {
+        Ok((changes, cluster)) => {
+            report_changes(&changes);
+            status::set(juju::Status {
                 status_type: juju::StatusType::Maintenance,
-                message: message,
+                message: format!("Crushmap for {} updated in {}. Please examine crushmap with \
+                                  Ceph before use.",
+                                 cluster.name,
+                                 cluster.path("").display()),
             });
         }
+        Err(e) => {
+            let message = format!("Failed to update crushmap with error: {}", e);
+            juju::log(&message, Some(LogLevel::Error));
+            let _ = juju::action_fail(&message);
+        }
+    }
+}
+
+fn edit_units(cluster: &cluster::Cluster, adding: bool) -> Result<(), String> {
+    let names: Vec<String> = match juju::action_get("name") {
+        Ok(names) => {
+            names.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string())
+                .collect()
+        }
+        Err(e) => return Err(e.to_string()),
     };
-    juju::log(format!("{:?}", crush_result), Some(LogLevel::Info));
-    println!("{:?}", crush_result);
+    if names.is_empty() {
+        return Err("No unit names were given".to_string());
+    }
+    let path = juju::action_get("path").unwrap_or_default().trim().to_string();
+
+    let options = try!(crushmap_options());
+    let mut history = try!(topology::history(cluster));
+    let mut topology = match history.pop() {
+        Some((_, topology)) => topology,
+        None => {
+            return Err(format!("No topology has been recorded for {}, run create-crushmap \
+                                first",
+                               cluster.name))
+        }
+    };
+    // Hosts any run has placed, so the ones left out of the edited topology are kept unlinked
+    // rather than dropped along with their devices
+    let mut known: BTreeSet<String> = BTreeSet::new();
+    for topology in history.iter().map(|(_, earlier)| earlier).chain(Some(&topology)) {
+        known.extend(topology.placements().keys().map(|host| host.to_string()));
+    }
+
+    for name in &names {
+        if adding {
+            try!(units::add_unit(&mut topology, name, &path));
+        } else {
+            try!(units::remove_unit(&mut topology, name, &path));
+        }
+    }
+
+    let racks: BTreeMap<String, Vec<String>> = topology.racks
+        .iter()
+        .map(|(rack, hosts)| (rack.clone(), hosts.iter().cloned().collect()))
+        .collect();
+    let mut map = try!(generate_crushmap(cluster, racks, &options));
+    let cluster_map = try!(crushtool::decode_crushmap(&try!(read_crushmap(cluster,
+                                                                           "currentmap"))[..]));
+    let placements = topology.placements();
+    for host in &known {
+        let in_cluster = cluster_map.name_map.iter().any(|(_, name)| name == host);
+        if !placements.contains_key(host.as_str()) && in_cluster {
+            try!(units::unlink(&mut map, &cluster_map, host));
+        }
+    }
+    if adding {
+        try!(units::check_path(&map, &path));
+    }
+    try!(write_crushmap(cluster, map));
+    try!(topology::save(cluster, &topology));
+    Ok(())
+}
+
+// Hints come from the file named by the hints-file action parameter, or else the rack-hints
+// charm config
+fn load_hints() -> Result<hints::Hints, String> {
+    let text = match juju::action_get("hints-file") {
+        Ok(ref path) if !path.trim().is_empty() => {
+            let mut text = String::new();
+            try!(File::open(path.trim())
+                .and_then(|mut file| file.read_to_string(&mut text))
+                .map_err(|e| format!("Could not read {}: {}", path.trim(), e)));
+            text
+        }
+        _ => juju::config_get("rack-hints").unwrap_or_default(),
+    };
+    if text.trim().is_empty() {
+        return Ok(hints::Hints::empty());
+    }
+    hints::parse(&text)
+}
 
+// When the action is given an edited text crushmap, it is compiled into the cluster's
+// dct_crushmap so it takes the place of the generated map
+fn load_text_map(cluster: &cluster::Cluster) -> Result<(), String> {
+    let path = match juju::action_get("text-map") {
+        Ok(ref path) if !path.trim().is_empty() => path.trim().to_string(),
+        _ => return Ok(()),
+    };
+    let mut text = String::new();
+    try!(File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|e| format!("Could not read {}: {}", path, e)));
+    let map = try!(text::parse(&text).map_err(|e| format!("{}: {}", path, e)));
+    juju::log(format!("Using the crushmap in {}", path), Some(LogLevel::Info));
+    write_crushmap(cluster, map)
+}
+
+// Compares the map fetched by begin-discovery with the one we generated
+fn diff_against_current(cluster: &cluster::Cluster) -> Result<Vec<String>, String> {
+    let current_map = try!(crushtool::decode_crushmap(&try!(read_crushmap(cluster,
+                                                                           "currentmap"))[..]));
+    let new_map = try!(crushtool::decode_crushmap(&try!(read_crushmap(cluster,
+                                                                       "dct_crushmap"))[..]));
+    Ok(diff::diff_crushmaps(&current_map, &new_map))
+}
+
+// Runs a sample of placement groups through both maps to see how many would move
+fn estimate_movement(cluster: &cluster::Cluster) -> Result<simulate::Movement, String> {
+    let current_map = try!(crushtool::decode_crushmap(&try!(read_crushmap(cluster,
+                                                                           "currentmap"))[..]));
+    let new_map = try!(crushtool::decode_crushmap(&try!(read_crushmap(cluster,
+                                                                       "dct_crushmap"))[..]));
+    simulate::estimate_movement(&current_map,
+                                &new_map,
+                                action_param("ruleset", 0),
+                                action_param("replicas", 3),
+                                action_param("pg-samples", 1024))
+}
+
+fn report_movement(movement: &simulate::Movement) {
+    let summary = format!("{} of {} sampled placement groups ({:.1}%) would change OSDs, moving \
+                           {:.1}% of replicas",
+                          movement.changed,
+                          movement.sampled,
+                          movement.changed_fraction() * 100.0,
+                          movement.replica_fraction() * 100.0);
+    println!("{}", summary);
+    let _ = juju::action_set("movement.summary", &summary);
+    let _ = juju::action_set("movement.changed-fraction",
+                             &format!("{:.4}", movement.changed_fraction()));
+    if movement.incomplete > 0 {
+        let warning = format!("{} sampled placement groups could not be fully placed by the new \
+                               map",
+                              movement.incomplete);
+        juju::log(&warning, Some(LogLevel::Warn));
+        let _ = juju::action_set("movement.incomplete", &movement.incomplete.to_string());
+    }
 }
 
-fn grab_relation_data() -> HashMap<String, Vec<String>> {
-    let juju_relation_ids = match juju::relation_ids_by_identifier("controller") {
-        Ok(ids) => ids,
-        Err(_) => {
-            juju::log("Failed at grabbing relation IDs.", Some(LogLevel::Error));
-            panic!("Failed at grabbing relation IDs.");
+// Reads an action parameter, falling back to the default when it is missing or unparseable
+fn action_param<T: FromStr>(key: &str, default: T) -> T {
+    match juju::action_get(key) {
+        Ok(value) => value.parse::<T>().unwrap_or(default),
+        Err(_) => default,
+    }
+}
+
+// Adds the topology to the cluster's history and reports how it differs from the last one, so
+// moved servers and cabling changes stand out
+fn record_topology(cluster: &cluster::Cluster, topology: &topology::Topology) {
+    let previous = match topology::history(cluster) {
+        Ok(mut history) => history.pop(),
+        Err(e) => {
+            juju::log(format!("Could not read the topology history: {}", e),
+                      Some(LogLevel::Warn));
+            None
         }
     };
-    let relation_id = &juju_relation_ids[0];
-    let controller_id = match env::var("JUJU_UNIT_NAME") {
-        Ok(id) => id,
-        Err(_) => {
-            juju::log("Failed to grab controller id from JUJU.",
-                      Some(LogLevel::Error));
-            panic!("Failed to grab controller id from JUJU.");
+    if let Err(e) = topology::save(cluster, topology) {
+        juju::log(format!("Could not save the topology: {}", e), Some(LogLevel::Warn));
+    }
+    let (time, previous) = match previous {
+        Some(previous) => previous,
+        None => {
+            let _ = juju::action_set("topology-drift", "No earlier topology to compare with");
+            return;
         }
+    };
 
+    let drift = topology::compare(&previous, topology);
+    let changes = drift.describe();
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !drift.is_empty() {
+        juju::log(format!("The topology changed since {}: {}", time, changes.join("; ")),
+                  Some(LogLevel::Warn));
+    }
+    let summary = if drift.is_empty() {
+        format!("No changes since {}", time)
+    } else {
+        changes.join("\n")
     };
+    let _ = juju::action_set("topology-drift", &summary);
+    let _ = juju::action_set("topology-drift-count", &changes.len().to_string());
+}
 
-    let controller = parse_unit_into_relation(controller_id);
+// Lists the topologies found by earlier runs, oldest first, each with what changed since the
+// one before it. Times are unix times.
+fn topology_history_action() {
+    let result = cluster::select().and_then(|cluster| topology::history(&cluster));
+    let history = match result {
+        Ok(history) => history,
+        Err(e) => {
+            let _ = juju::action_fail(&format!("Could not read the topology history: {}", e));
+            return;
+        }
+    };
 
-    let juju_related_units =
-        match juju::relation_get_by_id("related-units", &relation_id, &controller) {
-            Ok(units) => units,
-            Err(_) => {
-                juju::log("Failed to grab related units from juju relation.",
-                          Some(LogLevel::Error));
-                panic!("Failed to grab related units from juju relation.");
+    let mut text = String::new();
+    let mut previous: Option<&topology::Topology> = None;
+    for &(time, ref topology) in &history {
+        text.push_str(&format!("{}: {}\n", time, topology.summary()));
+        if let Some(previous) = previous {
+            for change in topology::compare(previous, topology).describe() {
+                text.push_str(&format!("  {}\n", change));
             }
+        }
+        previous = Some(topology);
+    }
+    if history.is_empty() {
+        text.push_str("No topology has been recorded yet\n");
+    }
+    println!("{}", text);
+    let _ = juju::action_set("history", &text);
+    let _ = juju::action_set("count", &history.len().to_string());
+}
 
-        };
+fn report_changes(changes: &[String]) {
+    for change in changes {
+        println!("{}", change);
+    }
+    let summary = if changes.is_empty() {
+        "No changes".to_string()
+    } else {
+        changes.join("\n")
+    };
+    let _ = juju::action_set("changes", &summary);
+    let _ = juju::action_set("change-count", &changes.len().to_string());
+}
 
+// Reads every node's hostname and neighbors from the cluster's relation. Nodes whose data is
+// missing or stale are logged and left out. Only the controller's own settings being unreadable
+// is an error, since there is no telling which nodes are members without them.
+fn grab_relation_data(cluster: &cluster::Cluster)
+                      -> Result<HashMap<String, Vec<String>>, String> {
+    let relation_id = &cluster.relation_id;
+    let controller = try!(cluster::controller_unit()
+        .map_err(|e| format!("Failed to grab controller id from JUJU: {}", e)));
+
+    let juju_related_units =
+        try!(juju::relation_get_by_id("related-units", relation_id, &controller)
+            .map_err(|e| format!("Failed to grab related units from juju relation: {}",
+                                 e.to_string())));
+
+    // Units that departed after the list was last published are left out, and so are units
+    // whose settings can't be read
+    let current_units: Option<Vec<juju::Relation>> = juju::relation_list_by_id(relation_id).ok();
     let mut juju_parsed_units: Vec<juju::Relation> = Vec::new();
 
     for unit in juju_related_units.split_whitespace() {
-        juju_parsed_units.push(parse_unit_into_relation(unit.to_string()));
+        let unit = match parse_unit_into_relation(unit.to_string()) {
+            Ok(unit) => unit,
+            Err(e) => {
+                juju::log(format!("{} Leaving it out.", e), Some(LogLevel::Warn));
+                continue;
+            }
+        };
+        if let Some(ref current_units) = current_units {
+            let still_related = current_units.iter()
+                .any(|current| current.name == unit.name && current.id == unit.id);
+            if !still_related {
+                juju::log(format!("Ignoring {:?}, it has left the relation", unit),
+                          Some(LogLevel::Warn));
+                continue;
+            }
+        }
+        juju_parsed_units.push(unit);
     }
 
+    // begin-discovery numbers each round. Nodes that haven't finished the current round still
+    // hold neighbors from an older one, which may no longer be true, so they are left out.
+    // Without an epoch discovery predates the counter and everything is used.
+    let epoch: Option<u64> = juju::relation_get_by_id("epoch", relation_id, &controller)
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok());
+
     let mut machines: HashMap<String, Vec<String>> = HashMap::new();
+    let mut member_hostnames: HashSet<String> = HashSet::new();
 
     for unit in juju_parsed_units {
-        let hostname = match juju::relation_get_by_id("hostname", &relation_id, &unit) {
-            Ok(h) => h,
-            Err(_) => {
-                juju::log(format!("Failed to grab hostname from {:?}.", unit),
-                          Some(LogLevel::Error));
-                panic!("Failed to grab hostname from {:?}.", unit);
+        let hostname = match juju::relation_get_by_id("hostname", relation_id, &unit) {
+            Ok(ref h) if !h.trim().is_empty() => h.clone(),
+            _ => {
+                juju::log(format!("Failed to grab hostname from {:?}, leaving it out.", unit),
+                          Some(LogLevel::Warn));
+                continue;
             }
 
         };
-        let neighbors_raw = match juju::relation_get_by_id("neighbors", &relation_id, &unit) {
+        let hostname_trimmed = hostname.trim_matches('\n').trim();
+        member_hostnames.insert(hostname_trimmed.to_owned());
+
+        if let Some(epoch) = epoch {
+            let finished = juju::relation_get_by_id("finished", relation_id, &unit)
+                .ok()
+                .and_then(|finished| finished.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            if finished != epoch {
+                juju::log(format!("Ignoring {:?}, its neighbors are from discovery epoch {} \
+                                   rather than {}",
+                                  unit,
+                                  finished,
+                                  epoch),
+                          Some(LogLevel::Warn));
+                continue;
+            }
+        }
+        let neighbors_raw = match juju::relation_get_by_id("neighbors", relation_id, &unit) {
             Ok(n) => n,
             Err(_) => {
-                juju::log(format!("Failed to grab neighbors from {:?}.", unit),
-                          Some(LogLevel::Error));
-                panic!("Failed to grab neighbors from {:?}.", unit);
+                juju::log(format!("Failed to grab neighbors from {:?}, leaving it out.", unit),
+                          Some(LogLevel::Warn));
+                continue;
             }
 
         };
-        let hostname_trimmed = hostname.trim_matches('\n').trim();
         let neighbors_trimmed = neighbors_raw.trim_matches('\n').trim();
 
         let neighbors: Vec<String> = neighbors_trimmed.split_whitespace()
@@ -114,7 +865,16 @@ fn grab_relation_data() -> HashMap<String, Vec<String>> {
         machines.insert(hostname_trimmed.to_owned(), neighbors);
     }
 
-    machines
+    // Neighbors reported before a host departed would pull it back into a rack
+    for (machine, neighbors) in machines.iter_mut() {
+        let before = neighbors.len();
+        neighbors.retain(|neighbor| member_hostnames.contains(neighbor));
+        if neighbors.len() < before {
+            println!("Dropped {} departed neighbors of {}", before - neighbors.len(), machine);
+        }
+    }
+
+    Ok(machines)
 }
 
 fn generate_racks(machines: HashMap<String, Vec<String>>) -> HashSet<Vec<String>> {
@@ -160,38 +920,99 @@ fn generate_racks(machines: HashMap<String, Vec<String>>) -> HashSet<Vec<String>
     racks
 }
 
-fn generate_crushmap(racks: HashSet<Vec<String>>) -> Result<(), String> {
+fn config_flag(key: &str) -> bool {
+    match juju::config_get(key) {
+        Ok(value) => value.to_lowercase() == "true",
+        Err(_) => false,
+    }
+}
+
+fn config_number(key: &str, default: u64) -> u64 {
+    match juju::config_get(key) {
+        Ok(value) => value.trim().parse::<u64>().unwrap_or(default),
+        Err(_) => default,
+    }
+}
+
+fn generate_crushmap(cluster: &cluster::Cluster,
+                     racks: BTreeMap<String, Vec<String>>,
+                     options: &CrushmapOptions)
+                     -> Result<crushtool::CrushMap, String> {
     // This generates a crushmap using the information gathered during network discovery.
     //
     // First it loads the current crushmap generated in the begin-discovery action.
     // Then it picks apart that map to get the name_map, buckets, and current index. From there we
     // take our list of racks, take each item in the rack and match it up to an item in the name
     // map for further use. We take those racks again and create a bucket for each one which holds
-    // the machine and the associated OSD. Finally put those buckets back into a crushmap and
-    // encode it with Crushtool, and write the bytes to a file that Ceph can use.
+    // the machine and the associated OSD. Finally put those buckets back into a crushmap, which
+    // write_crushmap encodes for Ceph. The rules are generated last so that replicas are spread
+    // across the failure domain we just built.
+    //
+    // In incremental mode the current map is kept and the racks are inserted into it instead,
+    // see incremental.rs.
 
 
     // Open that map and read the bytes to a var, then decode those bytes to a crushmap object
-    let mut path = env::temp_dir();
-    path.push("currentmap");
-    let mut some_crushmap_file = try!(File::open(path).map_err(|e| e.to_string()));
-    let mut crushmap_bytes: Vec<u8> = Vec::new();
-    let _ = some_crushmap_file.read_to_end(&mut crushmap_bytes);
+    let crushmap_bytes: Vec<u8> = try!(read_crushmap(cluster, "currentmap"));
     // The actual Ceph crushmap pulled from our active cluster
     let current_map: crushtool::CrushMap = try!(crushtool::decode_crushmap(&crushmap_bytes[..]));
 
-    // Grab the current lowest index. For bucket objects like racks these are always negative
-    let mut current_index: i32 = match current_map.name_map.iter().min() {
-        Some(&(index, _)) => index,
+    // Crushtool stops decoding after the Jewel tunables. Anything past that, such as device
+    // classes, would be silently lost when the map is encoded again. An incremental map built on
+    // top of it would look like the current map but quietly lose them, so the whole map is
+    // rebuilt instead and the operator told why.
+    let understood_bytes = try!(crushtool::encode_crushmap(current_map.clone())
+        .map_err(|e| e.to_string()));
+    let mut incremental = options.incremental;
+    if understood_bytes.len() < crushmap_bytes.len() {
+        let mut message = format!("The current crushmap has {} bytes crushtool does not \
+                                   understand (device classes or newer tunables). They will not \
+                                   be carried over.",
+                                  crushmap_bytes.len() - understood_bytes.len());
+        if incremental {
+            message.push_str(" Incremental mode cannot keep the rest of the map intact, so the \
+                              whole crushmap is rebuilt instead.");
+            let _ = juju::action_set("warning", &message);
+            incremental = false;
+        }
+        juju::log(&message, Some(LogLevel::Warn));
+    }
+
+    // New buckets use the configured algorithm, or whatever most of the current buckets use
+    let alg = match options.bucket_algorithm {
+        Some(ref alg) => alg.clone(),
         None => {
-            return Err("Cannot proceed due to error: Could not find current index.
-                    Either no bucket items are present or map decode failed to generate meaningful
-                    buckets."
-                .to_string());
+            buckets::most_common_alg(&current_map.buckets)
+                .unwrap_or(crushtool::BucketAlg::Straw2)
         }
     };
-    // Set the current index for the next item
-    current_index -= 1;
+    println!("Bucket algorithm: {:?}", alg);
+
+    if incremental {
+        let straw_calc_version = current_map.straw_calc_version.unwrap_or(0);
+        let (mut new_crushmap, root) = try!(incremental::insert_racks(current_map,
+                                                                      racks,
+                                                                      alg.clone()));
+        try!(hints::add_levels(&mut new_crushmap,
+                               &options.hints.levels,
+                               alg,
+                               straw_calc_version));
+        try!(rules::merge_rules(&mut new_crushmap,
+                                root,
+                                &options.failure_domain,
+                                options.erasure_coded));
+        return Ok(new_crushmap);
+    }
+
+    if current_map.name_map.is_empty() {
+        return Err("Cannot proceed due to error: Could not find current index.
+                Either no bucket items are present or map decode failed to generate meaningful
+                buckets."
+            .to_string());
+    }
+    // Grab the index for the next bucket. For bucket objects like racks these are always
+    // negative, and buckets without a name still hold on to their id
+    let mut current_index: i32 = buckets::next_id(&current_map.buckets, &current_map.name_map);
     println!("Current index: {}", current_index);
 
     // Name map and current buckets are pulled out
@@ -203,8 +1024,8 @@ fn generate_crushmap(racks: HashSet<Vec<String>>) -> Result<(), String> {
 
 
     let mut machines: HashSet<String> = HashSet::new();
-    for members in racks.clone() {
-        machines.extend(members);
+    for members in racks.values() {
+        machines.extend(members.iter().cloned());
     }
     println!("machines: {:?}", machines);
 
@@ -279,28 +1100,31 @@ fn generate_crushmap(racks: HashSet<Vec<String>>) -> Result<(), String> {
     // index, keeping the tree below untouched.
 
     let mut new_rack_items: Vec<(i32, Option<String>)> = Vec::new();
-    let mut bucket_name: i32 = 0;
-    let mut running_weight: u32 = 0;
-    let mut default_bucket_weights: Vec<(u32, u32)> = Vec::new();
+    let mut default_bucket_weights: Vec<u32> = Vec::new();
     // For each group of machines in our racks var we make a bucket
-    for members in racks {
+    for (rack_name, members) in racks {
         let mut bucket_items: Vec<(i32, Option<String>)> = Vec::new();
-        let mut item_weights: Vec<(u32, u32)> = Vec::new();
+        let mut item_weights: Vec<u32> = Vec::new();
 
         // For each machine in the machines map we grab the bucket items from out machines map.
         // These are matched by the machine's ID
         for machine in &members {
             let index: i32 = match machines_map.get(machine) {
                 Some(index) => *index,
-                None => return Err("Could not match bucket items to machine index".to_string()),
+                None => return Err(format!("{} is not in the current crushmap", machine)),
             };
             // Again, since we're only concerned with the index of the machine
             // (the root of our machine/osd tree)
             // we only push that index into our bucket items list, along with the corresponding
             // machine name
             let weight: u32;
-            // Pull the bucket out by index, grab weight
-            let bucket = weighty_buckets.get(machine).unwrap();
+            // Pull the bucket out by index, grab weight. Hints can pin a host that has no bucket.
+            let bucket = match weighty_buckets.get(machine) {
+                Some(bucket) => bucket,
+                None => {
+                    return Err(format!("{} has no host bucket in the current crushmap", machine))
+                }
+            };
 
             match bucket {
                 &crushtool::BucketTypes::Uniform(ref uniform) => {
@@ -324,47 +1148,28 @@ fn generate_crushmap(racks: HashSet<Vec<String>>) -> Result<(), String> {
             };
 
             bucket_items.push((index, Some(machine.to_string())));
-            item_weights.push((weight, 65536));
+            item_weights.push(weight);
         }
-        let total_weight = item_weights.iter().fold(0, |acc, &(x, _)| acc + x);
-        running_weight += total_weight;
         // Make a new bucket, put the items matched above into it, then push it to our rack buckets
-        let bucket = crushtool::BucketTypes::Straw(crushtool::CrushBucketStraw {
-            bucket: crushtool::Bucket {
-                id: current_index,
-                bucket_type: crushtool::OpCode::ChooseIndep,
-                alg: crushtool::BucketAlg::Straw,
-                hash: crushtool::CrushHash::RJenkins1,
-                weight: total_weight,
-                size: members.len() as u32,
-                items: bucket_items,
-                perm_n: 0,
-                perm: members.len() as u32,
-            },
-            item_weights: item_weights,
-        });
-        final_name_map.push((current_index, format!("Rack: {}", bucket_name.to_string())));
-        new_rack_items.push((current_index, Some(format!("Rack: {}", bucket_name.to_string()))));
+        let mut bucket = buckets::new_bucket(current_index,
+                                             crushtool::OpCode::ChooseIndep,
+                                             alg.clone());
+        try!(buckets::set_items(&mut bucket, bucket_items, item_weights, STRAW_CALC_VERSION));
+        let total_weight = buckets::weight(&bucket);
+        final_name_map.push((current_index, rack_name.clone()));
+        new_rack_items.push((current_index, Some(rack_name)));
         new_rack_buckets.push(bucket);
         current_index -= 1;
-        bucket_name += 1;
-        default_bucket_weights.push((total_weight, 65536));
+        default_bucket_weights.push(total_weight);
     }
     // Make a new default bucket
-    let new_default_bucket = crushtool::BucketTypes::Straw(crushtool::CrushBucketStraw {
-        bucket: crushtool::Bucket {
-            id: -1,
-            bucket_type: crushtool::OpCode::SetChooseLocalTries,
-            alg: crushtool::BucketAlg::Straw,
-            hash: crushtool::CrushHash::RJenkins1,
-            weight: running_weight,
-            size: new_rack_buckets.len() as u32,
-            items: new_rack_items,
-            perm_n: 0,
-            perm: new_rack_buckets.len() as u32,
-        },
-        item_weights: default_bucket_weights,
-    });
+    let mut new_default_bucket =
+        buckets::new_bucket(-1, crushtool::OpCode::SetChooseLocalTries, alg.clone());
+    try!(buckets::set_items(&mut new_default_bucket,
+                            new_rack_items,
+                            default_bucket_weights,
+                            STRAW_CALC_VERSION));
+    let rack_count = new_rack_buckets.len();
     final_name_map.push((-1, "default".to_string()));
     final_name_map.sort();
     println!("Final name map:{:?}", final_name_map);
@@ -374,96 +1179,151 @@ fn generate_crushmap(racks: HashSet<Vec<String>>) -> Result<(), String> {
     final_buckets.extend(carryover_buckets);
     final_buckets.extend(new_rack_buckets);
 
+    if rack_count < 2 && options.failure_domain == "rack" {
+        juju::log(format!("Only {} rack(s) discovered, replicas cannot be spread across racks.",
+                          rack_count),
+                  Some(LogLevel::Warn));
+    }
+    let (rules, rule_name_map) =
+        try!(rules::generate_rules(-1,
+                                   &options.failure_domain,
+                                   &default_type_map(),
+                                   options.erasure_coded,
+                                   ""));
 
-    create_crushmap(final_buckets, machines_map.len() as i32, final_name_map)
+    create_crushmap(final_buckets,
+                    final_name_map,
+                    rules,
+                    rule_name_map,
+                    &options.hints.levels,
+                    alg)
 }
 
 fn create_crushmap(final_buckets: Vec<crushtool::BucketTypes>,
-                   devices: i32,
-                   final_name_map: Vec<(i32, String)>)
-                   -> Result<(), String> {
+                   final_name_map: Vec<(i32, String)>,
+                   rules: Vec<Option<crushtool::Rule>>,
+                   rule_name_map: Vec<(i32, String)>,
+                   levels: &[hints::Level],
+                   alg: crushtool::BucketAlg)
+                   -> Result<crushtool::CrushMap, String> {
+
+    // Ceph finds a bucket by its id, at slot -1-id, so max_buckets has to reach the most
+    // negative id rather than count the buckets. Likewise max_devices has to reach past the
+    // highest OSD id.
+    let mut placed_buckets: Vec<crushtool::BucketTypes> = Vec::new();
+    for bucket in final_buckets {
+        buckets::place(&mut placed_buckets, bucket);
+    }
+    let devices = buckets::device_bound(&placed_buckets, &final_name_map);
 
     let mut new_crushmap: crushtool::CrushMap = crushtool::CrushMap {
         magic: 65536,
-        max_buckets: final_buckets.len() as i32,
-        max_rules: 1,
+        max_buckets: placed_buckets.len() as i32,
+        max_rules: rules.len() as u32,
         max_devices: devices,
-        buckets: final_buckets,
-        rules: vec![Some(crushtool::Rule {
-                        mask: crushtool::CrushRuleMask {
-                            ruleset: 0,
-                            rule_type: crushtool::RuleType::Replicated,
-                            min_size: 1,
-                            max_size: 10,
-                        },
-                        steps: vec![crushtool::CrushRuleStep {
-                                        op: crushtool::OpCode::Take,
-                                        arg1: (-1, None),
-                                        arg2: (0, None),
-                                    },
-                                    crushtool::CrushRuleStep {
-                                        op: crushtool::OpCode::ChooseLeafFirstN,
-                                        arg1: (0, None),
-                                        arg2: (1, None),
-                                    },
-                                    crushtool::CrushRuleStep {
-                                        op: crushtool::OpCode::Emit,
-                                        arg1: (0, None),
-                                        arg2: (0, None),
-                                    }],
-                    })],
-        type_map: vec![(0, "osd".to_string()),
-                       (1, "host".to_string()),
-                       (2, "chassis".to_string()),
-                       (3, "rack".to_string()),
-                       (4, "row".to_string()),
-                       (5, "pdu".to_string()),
-                       (6, "pod".to_string()),
-                       (7, "room".to_string()),
-                       (8, "datacenter".to_string()),
-                       (9, "region".to_string()),
-                       (10, "root".to_string())],
+        buckets: placed_buckets,
+        rules: rules,
+        type_map: default_type_map(),
 
         name_map: final_name_map,
-        rule_name_map: vec![(0, "replicated_ruleset".to_string())],
+        rule_name_map: rule_name_map,
         choose_local_tries: Some(0),
         choose_local_fallback_tries: Some(0),
         choose_total_tries: Some(50),
         chooseleaf_descend_once: Some(1),
         chooseleaf_vary_r: Some(0),
-        straw_calc_version: Some(1),
+        straw_calc_version: Some(STRAW_CALC_VERSION),
         allowed_bucket_algorithms: Some(0),
         chooseleaf_stable: Some(0),
     };
-    println!("New Crushmap: {:?}", new_crushmap);
+    try!(hints::add_levels(&mut new_crushmap, levels, alg, STRAW_CALC_VERSION));
     crushtool::set_tunables_jewel(&mut new_crushmap);
+    // Jewel leaves tree buckets out of the allowed algorithms, so allow whatever we generated
+    let mut allowed = new_crushmap.allowed_bucket_algorithms.unwrap_or(0);
+    for bucket in &new_crushmap.buckets {
+        if let Some(header) = buckets::header(bucket) {
+            allowed |= 1 << (header.alg.clone() as u32);
+        }
+    }
+    new_crushmap.allowed_bucket_algorithms = Some(allowed);
+    Ok(new_crushmap)
+}
+
+// Encodes the map and writes it to the cluster's dct_crushmap for Ceph to pick up. The
+// decompiled text goes next to it in dct_crushmap.txt for review, and a copy is kept as a
+// crushmap snapshot. Maps that fail validation are not written.
+fn write_crushmap(cluster: &cluster::Cluster, new_crushmap: crushtool::CrushMap)
+                  -> Result<(), String> {
+    try!(check_crushmap(&new_crushmap));
+    let crushmap_text = text::render(&new_crushmap);
+    println!("New Crushmap:\n{}", crushmap_text);
+    let mut text_file = try!(File::create(cluster.path("dct_crushmap.txt"))
+        .map_err(|e| e.to_string()));
+    try!(text_file.write_all(crushmap_text.as_bytes()).map_err(|e| e.to_string()));
+
     let encoded_crushmap = try!(crushtool::encode_crushmap(new_crushmap)
         .map_err(|e| e.to_string()));
-    let mut path = env::temp_dir();
-    path.push("dct_crushmap");
-    let mut finished_map = try!(File::create(path).map_err(|e| e.to_string()));
+    let mut finished_map = try!(File::create(cluster.path("dct_crushmap"))
+        .map_err(|e| e.to_string()));
 
     try!(finished_map.write_all(&encoded_crushmap[..]).map_err(|e| e.to_string()));
+    if let Err(e) = state::snapshot(cluster, "crushmap", &encoded_crushmap[..]) {
+        juju::log(format!("Could not save a snapshot of the crushmap: {}", e),
+                  Some(LogLevel::Warn));
+    }
 
     Ok(())
 }
 
-// Parses unit strings from Juju into relations that Crushtool can understand
-fn parse_unit_into_relation(unit: String) -> juju::Relation {
-    let v: Vec<&str> = unit.split('/').collect();
-    let id: usize = match v[1].parse::<usize>() {
-        Ok(i) => i,
-        Err(_) => {
-            juju::log(format!("Could not parse {} into relation.", unit),
-                      Some(LogLevel::Error));
-            panic!("Could not parse {} into relation.", unit)
+// Runs the validator over a map, logging warnings and failing with every error found
+fn check_crushmap(crushmap: &crushtool::CrushMap) -> Result<(), String> {
+    let report = validate::validate(crushmap);
+    for warning in &report.warnings {
+        juju::log(format!("Crushmap warning: {}", warning), Some(LogLevel::Warn));
+    }
+    if !report.is_valid() {
+        for error in &report.errors {
+            juju::log(format!("Crushmap error: {}", error), Some(LogLevel::Error));
         }
+        return Err(format!("The crushmap is invalid: {}", report.errors.join("; ")));
+    }
+    Ok(())
+}
 
+// Reads one of the cluster's crushmap files
+fn read_crushmap(cluster: &cluster::Cluster, name: &str) -> Result<Vec<u8>, String> {
+    let mut crushmap_file = try!(File::open(cluster.path(name)).map_err(|e| e.to_string()));
+    let mut crushmap_bytes: Vec<u8> = Vec::new();
+    try!(crushmap_file.read_to_end(&mut crushmap_bytes).map_err(|e| e.to_string()));
+    Ok(crushmap_bytes)
+}
+
+// The standard Ceph bucket types. Rack buckets are type 3 and the root is type 10.
+fn default_type_map() -> Vec<(i32, String)> {
+    vec![(0, "osd".to_string()),
+         (1, "host".to_string()),
+         (2, "chassis".to_string()),
+         (3, "rack".to_string()),
+         (4, "row".to_string()),
+         (5, "pdu".to_string()),
+         (6, "pod".to_string()),
+         (7, "room".to_string()),
+         (8, "datacenter".to_string()),
+         (9, "region".to_string()),
+         (10, "root".to_string())]
+}
+
+// Parses unit strings from Juju into relations that Crushtool can understand
+fn parse_unit_into_relation(unit: String) -> Result<juju::Relation, String> {
+    let v: Vec<&str> = unit.split('/').collect();
+    let id: usize = match v.get(1).and_then(|id| id.parse::<usize>().ok()) {
+        Some(i) if v.len() == 2 => i,
+        _ => return Err(format!("Could not parse {} into relation.", unit)),
     };
 
     let parsed_unit = juju::Relation {
         name: v[0].to_string(),
         id: id,
     };
-    parsed_unit
+    Ok(parsed_unit)
 }
@@ -0,0 +1,54 @@
+use juju;
+use log::LogLevel;
+use std::env;
+
+use status;
+
+// Membership is whatever is related right now. The list is republished on the joining or
+// departing unit's relation every time a unit joins or departs, and begin-discovery decides
+// whether there are enough units to start, see discovery.rs.
+pub fn publish(hook: &str) {
+    let mut units = match juju::relation_list() {
+        Ok(units) => units,
+        Err(e) => {
+            juju::log(format!("Could not list the related units: {}", e.to_string()),
+                      Some(LogLevel::Warn));
+            return;
+        }
+    };
+
+    // The departing unit may still be listed while its departed hook runs
+    if hook == "controller-relation-departed" {
+        let departing = env::var("JUJU_REMOTE_UNIT").unwrap_or_default();
+        units.retain(|unit| format!("{}/{}", unit.name, unit.id) != departing);
+    }
+
+    let unit_list: Vec<String> = units.iter()
+        .map(|unit| format!("{}/{}", unit.name, unit.id))
+        .collect();
+    let _ = juju::relation_set("related-units", &unit_list.join(" "));
+
+    // num-units of 0 or unset means the operator hasn't said how many nodes to expect
+    let quorum = juju::config_get("num-units")
+        .ok()
+        .and_then(|num_units| num_units.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let message = if units.is_empty() {
+        "No nodes related yet".to_string()
+    } else if quorum == 0 {
+        format!("{} nodes related. Set num-units, or run begin-discovery with confirm=true once \
+                 every node is related",
+                units.len())
+    } else if units.len() >= quorum {
+        format!("{} nodes related, ready to begin network discovery", units.len())
+    } else {
+        format!("{} of {} nodes related, waiting for more or for begin-discovery with \
+                 confirm=true",
+                units.len(),
+                quorum)
+    };
+    status::set(juju::Status {
+        status_type: juju::StatusType::Waiting,
+        message: message,
+    });
+}
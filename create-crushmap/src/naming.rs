@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashSet};
+
+// Gives each discovered rack a name that stays the same from run to run.
+//
+// The racks are sorted by their sorted member lists before naming, so HashSet ordering never
+// leaks into the result. The template can use:
+//   {first}  the alphabetically first host in the rack
+//   {index}  the rack's position in that sorted order, starting at 0
+//   {hash}   a short hash of the sorted member list
+// {first} is the default since it only changes when a host that sorts earlier joins the rack.
+// Anything Ceph won't accept in a bucket name is replaced with a dash, and names that come out
+// the same are told apart with a numeric suffix. Racks pinned by the hints keep their given name.
+pub fn name_racks(racks: HashSet<Vec<String>>,
+                  template: &str,
+                  pinned: &BTreeMap<String, Vec<String>>)
+                  -> Result<BTreeMap<String, Vec<String>>, String> {
+    if !template.contains("{first}") && !template.contains("{index}") &&
+       !template.contains("{hash}") {
+        return Err(format!("Rack name template {} would give every rack the same name",
+                           template));
+    }
+
+    let mut sorted_racks: Vec<Vec<String>> = racks.into_iter()
+        .map(|mut members| {
+            members.sort();
+            members
+        })
+        .collect();
+    sorted_racks.sort();
+
+    let mut pinned_names: BTreeMap<Vec<String>, &String> = BTreeMap::new();
+    for (name, members) in pinned {
+        let mut members = members.clone();
+        members.sort();
+        pinned_names.insert(members, name);
+    }
+
+    let mut named: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (index, members) in sorted_racks.into_iter().enumerate() {
+        if let Some(name) = pinned_names.get(&members) {
+            named.insert((*name).clone(), members);
+            continue;
+        }
+        let first = members.first().cloned().unwrap_or_default();
+        let base = sanitize(&template.replace("{first}", &first)
+            .replace("{index}", &index.to_string())
+            .replace("{hash}", &format!("{:08x}", member_hash(&members))));
+        let mut name = base.clone();
+        let mut suffix = 2;
+        while named.contains_key(&name) || pinned.contains_key(&name) {
+            name = format!("{}-{}", base, suffix);
+            suffix += 1;
+        }
+        named.insert(name, members);
+    }
+    Ok(named)
+}
+
+// Ceph bucket names are limited to letters, digits, '-', '_' and '.'
+pub fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            let allowed = (c.is_alphanumeric() && (c as u32) < 128) || c == '-' || c == '_' ||
+                          c == '.';
+            if allowed { c } else { '-' }
+        })
+        .collect()
+}
+
+// 32 bit FNV-1a over the member names. The standard library hasher is not guaranteed to give
+// the same answer across Rust releases, and these names need to survive upgrades.
+fn member_hash(members: &[String]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for member in members {
+        for byte in member.bytes().chain(Some(0)) {
+            hash ^= byte as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashSet};
+
+    fn racks(racks: &[&[&str]]) -> HashSet<Vec<String>> {
+        racks.iter()
+            .map(|hosts| hosts.iter().map(|host| host.to_string()).collect())
+            .collect()
+    }
+
+    fn names(named: &BTreeMap<String, Vec<String>>) -> Vec<&str> {
+        named.keys().map(|name| name.as_str()).collect()
+    }
+
+    #[test]
+    fn names_follow_the_first_host() {
+        let named = name_racks(racks(&[&["host3", "host1"], &["host2"]]),
+                               "rack-{first}",
+                               &BTreeMap::new())
+            .unwrap();
+        assert_eq!(names(&named), vec!["rack-host1", "rack-host2"]);
+        assert_eq!(named["rack-host1"], vec!["host1".to_string(), "host3".to_string()]);
+    }
+
+    #[test]
+    fn names_do_not_depend_on_set_order() {
+        let template = "r{index}-{hash}";
+        let first = name_racks(racks(&[&["b", "a"], &["c"], &["e", "d"]]),
+                               template,
+                               &BTreeMap::new())
+            .unwrap();
+        let second = name_racks(racks(&[&["d", "e"], &["a", "b"], &["c"]]),
+                                template,
+                                &BTreeMap::new())
+            .unwrap();
+        assert_eq!(first, second);
+        assert!(first.contains_key(&format!("r1-{:08x}", member_hash(&["c".to_string()]))));
+    }
+
+    #[test]
+    fn hash_is_fnv1a() {
+        // FNV-1a of "a" is 0xe40c292c; each member is followed by a 0 byte
+        assert_eq!(member_hash(&["a".to_string()]), 0x2b24d044);
+        assert_eq!(member_hash(&["host1".to_string(), "host2".to_string()]),
+                   0xb4b056da);
+    }
+
+    #[test]
+    fn clashes_get_a_suffix() {
+        let mut pinned: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        pinned.insert("rack-host1".to_string(), vec!["host9".to_string()]);
+        let named = name_racks(racks(&[&["host1", "host2"], &["host9"]]), "rack-{first}", &pinned)
+            .unwrap();
+        assert_eq!(names(&named), vec!["rack-host1", "rack-host1-2"]);
+        assert_eq!(named["rack-host1"], vec!["host9".to_string()]);
+    }
+
+    #[test]
+    fn templates_must_vary() {
+        assert!(name_racks(racks(&[&["host1"]]), "rack", &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize("rack host/1.é"), "rack-host-1.-");
+        let named = name_racks(racks(&[&["web 1"]]), "{first}", &BTreeMap::new()).unwrap();
+        assert_eq!(names(&named), vec!["web-1"]);
+    }
+}
@@ -0,0 +1,256 @@
+use juju;
+use std::cmp;
+
+use status;
+
+// How far each node has got with the current round of discovery. Nodes set discovery-epoch,
+// discovery-state (running, finished or failed) and discovery-error on the controller relation
+// as they go. Nodes from before those keys existed only set finished, which still tells us when
+// they are done.
+
+#[derive(Debug, PartialEq)]
+pub enum UnitState {
+    Pending,
+    Running,
+    Finished,
+    Failed(String),
+}
+
+pub struct UnitProgress {
+    pub unit: String,
+    pub hostname: String,
+    pub state: UnitState,
+}
+
+pub struct Progress {
+    // The round begin-discovery last started, 0 if it never ran
+    pub epoch: u64,
+    pub units: Vec<UnitProgress>,
+}
+
+impl Progress {
+    pub fn finished(&self) -> usize {
+        self.units.iter().filter(|unit| unit.state == UnitState::Finished).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.units
+            .iter()
+            .filter(|unit| matches!(unit.state, UnitState::Failed(_)))
+            .count()
+    }
+
+    // Every node has either finished or given up
+    pub fn is_complete(&self) -> bool {
+        self.finished() + self.failed() == self.units.len()
+    }
+
+    pub fn summary(&self) -> String {
+        if self.epoch == 0 {
+            return format!("Discovery has not started, {} nodes related", self.units.len());
+        }
+        let mut summary = format!("{}/{} nodes finished discovery (epoch {})",
+                                  self.finished(),
+                                  self.units.len(),
+                                  self.epoch);
+        if self.failed() > 0 {
+            summary.push_str(&format!(", {} failed", self.failed()));
+        }
+        summary
+    }
+
+    // One line per node: unit, hostname, state and the reason for any failure
+    pub fn table(&self) -> String {
+        let mut table = String::from("UNIT\tHOSTNAME\tSTATE\n");
+        for unit in &self.units {
+            let state = match unit.state {
+                UnitState::Pending => "pending".to_string(),
+                UnitState::Running => "running".to_string(),
+                UnitState::Finished => "finished".to_string(),
+                UnitState::Failed(ref reason) => format!("failed: {}", reason),
+            };
+            table.push_str(&format!("{}\t{}\t{}\n", unit.unit, unit.hostname, state));
+        }
+        table
+    }
+}
+
+// Reads the state of every unit on the controller relation
+pub fn collect(relation_id: &juju::Relation,
+               controller: &juju::Relation)
+               -> Result<Progress, String> {
+    let get = |key: &str, unit: &juju::Relation| -> String {
+        match juju::relation_get_by_id(key, relation_id, unit) {
+            Ok(value) => value.trim().to_string(),
+            Err(_) => String::new(),
+        }
+    };
+
+    let epoch = round_epoch(|key| get(key, controller));
+
+    let mut units: Vec<UnitProgress> = Vec::new();
+    let mut related = try!(juju::relation_list_by_id(relation_id).map_err(|e| e.to_string()));
+    related.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id)));
+    for unit in related {
+        units.push(UnitProgress {
+            unit: format!("{}/{}", unit.name, unit.id),
+            hostname: get("hostname", &unit),
+            state: unit_state(epoch, |key| get(key, &unit)),
+        });
+    }
+
+    Ok(Progress {
+        epoch: epoch,
+        units: units,
+    })
+}
+
+// The round the controller started, from its own relation data. Controllers from before epochs
+// only set ready, which counts as epoch 1.
+fn round_epoch<F: Fn(&str) -> String>(get: F) -> u64 {
+    match get("epoch").parse::<u64>() {
+        Ok(epoch) => epoch,
+        Err(_) if get("ready") == "1" => 1,
+        Err(_) => 0,
+    }
+}
+
+// Where one unit is with the given round, from the unit's relation data. A unit still showing
+// an older round hasn't picked up this one yet.
+fn unit_state<F: Fn(&str) -> String>(epoch: u64, get: F) -> UnitState {
+    let finished = get("finished").parse::<u64>().unwrap_or(0);
+    let unit_epoch = get("discovery-epoch").parse::<u64>().unwrap_or(0);
+    if epoch == 0 {
+        UnitState::Pending
+    } else if finished == epoch {
+        UnitState::Finished
+    } else if unit_epoch != epoch {
+        UnitState::Pending
+    } else {
+        match get("discovery-state").as_str() {
+            "running" => UnitState::Running,
+            "failed" => UnitState::Failed(get("discovery-error")),
+            "finished" => UnitState::Finished,
+            _ => UnitState::Pending,
+        }
+    }
+}
+
+// Shows the progress as the controller's status, so the operator can tell when to run
+// create-crushmap without watching the debug log. With several clusters each one's summary is
+// prefixed with its name, and the status is the worst of them.
+pub fn set_status(clusters: &[(String, Progress)]) {
+    let mut rank = 0;
+    let mut messages: Vec<String> = Vec::new();
+    for (name, progress) in clusters {
+        if progress.epoch == 0 {
+            continue;
+        }
+        let (cluster_rank, mut message) = if !progress.is_complete() {
+            (1, progress.summary())
+        } else if progress.failed() > 0 {
+            (2, progress.summary())
+        } else {
+            (0, progress.summary())
+        };
+        if clusters.len() > 1 {
+            message = format!("{}: {}", name, message);
+        }
+        rank = cmp::max(rank, cluster_rank);
+        messages.push(message);
+    }
+    if messages.is_empty() {
+        return;
+    }
+    let (status_type, message) = match rank {
+        0 => {
+            (juju::StatusType::Active,
+             format!("{}, ready to create crushmap", messages.join("; ")))
+        }
+        1 => (juju::StatusType::Waiting, messages.join("; ")),
+        _ => (juju::StatusType::Blocked, messages.join("; ")),
+    };
+    status::set(juju::Status {
+        status_type: status_type,
+        message: message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn data(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn state(epoch: u64, pairs: &[(&str, &str)]) -> UnitState {
+        let data = data(pairs);
+        unit_state(epoch, |key| data.get(key).cloned().unwrap_or_default())
+    }
+
+    #[test]
+    fn rounds_come_from_the_epoch_or_ready() {
+        let epoch = |pairs: &[(&str, &str)]| {
+            let data = data(pairs);
+            round_epoch(|key| data.get(key).cloned().unwrap_or_default())
+        };
+        assert_eq!(epoch(&[("epoch", "4"), ("ready", "1")]), 4);
+        assert_eq!(epoch(&[("ready", "1")]), 1);
+        assert_eq!(epoch(&[("ready", "0")]), 0);
+        assert_eq!(epoch(&[]), 0);
+    }
+
+    #[test]
+    fn units_follow_the_current_round() {
+        assert_eq!(state(0, &[("finished", "0")]), UnitState::Pending);
+        assert_eq!(state(2, &[]), UnitState::Pending);
+        assert_eq!(state(2, &[("discovery-epoch", "2"), ("discovery-state", "running")]),
+                   UnitState::Running);
+        assert_eq!(state(2, &[("discovery-epoch", "2"), ("discovery-state", "finished")]),
+                   UnitState::Finished);
+        // Older nodes only set finished
+        assert_eq!(state(1, &[("finished", "1")]), UnitState::Finished);
+    }
+
+    #[test]
+    fn older_rounds_count_as_pending() {
+        let stale = [("finished", "1"),
+                     ("discovery-epoch", "1"),
+                     ("discovery-state", "failed"),
+                     ("discovery-error", "No network interfaces could be opened")];
+        assert_eq!(state(2, &stale), UnitState::Pending);
+        assert_eq!(state(2, &[("discovery-epoch", "1"), ("discovery-state", "running")]),
+                   UnitState::Pending);
+    }
+
+    #[test]
+    fn failures_keep_their_error() {
+        let failed = [("finished", "1"),
+                      ("discovery-epoch", "2"),
+                      ("discovery-state", "failed"),
+                      ("discovery-error", "No network interfaces could be opened")];
+        assert_eq!(state(2, &failed),
+                   UnitState::Failed("No network interfaces could be opened".to_string()));
+
+        let progress = Progress {
+            epoch: 2,
+            units: vec![UnitProgress {
+                            unit: "dct-node/0".to_string(),
+                            hostname: "host1".to_string(),
+                            state: state(2, &failed),
+                        },
+                        UnitProgress {
+                            unit: "dct-node/1".to_string(),
+                            hostname: "host2".to_string(),
+                            state: UnitState::Finished,
+                        }],
+        };
+        assert!(progress.is_complete());
+        assert_eq!(progress.summary(), "1/2 nodes finished discovery (epoch 2), 1 failed");
+        assert_eq!(progress.table(),
+                   "UNIT\tHOSTNAME\tSTATE\ndct-node/0\thost1\tfailed: No network interfaces \
+                    could be opened\ndct-node/1\thost2\tfinished\n");
+    }
+}
@@ -0,0 +1,243 @@
+use crushtool::{CrushMap, CrushRuleMask, CrushRuleStep, OpCode, Rule, RuleType};
+
+// Rules and the names that go with them, as they sit in a crushmap's rules and rule_name_map
+type NamedRules = (Vec<Option<Rule>>, Vec<(i32, String)>);
+
+// Builds the placement rules for the generated crushmap.
+//
+// The replicated rule always exists as ruleset 0 so pools created with the default ruleset keep
+// working. It descends from the root and picks one leaf per bucket of the failure domain type, so
+// with the default of "rack" no two replicas end up behind the same switch. The erasure coded
+// rule mirrors what `ceph osd erasure-code-profile` would generate, using the same failure domain.
+// Rule names are prefixed with name_prefix so they can sit alongside rules that already exist.
+pub fn generate_rules(root_id: i32,
+                      failure_domain: &str,
+                      type_map: &[(i32, String)],
+                      erasure_coded: bool,
+                      name_prefix: &str)
+                      -> Result<NamedRules, String> {
+
+    let domain_id = try!(type_id(failure_domain, type_map));
+
+    let mut rules: Vec<Option<Rule>> = Vec::new();
+    let mut rule_name_map: Vec<(i32, String)> = Vec::new();
+
+    rules.push(Some(Rule {
+        mask: CrushRuleMask {
+            ruleset: 0,
+            rule_type: RuleType::Replicated,
+            min_size: 1,
+            max_size: 10,
+        },
+        steps: vec![step(OpCode::Take, root_id, 0),
+                    step(OpCode::ChooseLeafFirstN, 0, domain_id),
+                    step(OpCode::Emit, 0, 0)],
+    }));
+    rule_name_map.push((0, format!("{}replicated_ruleset", name_prefix)));
+
+    if erasure_coded {
+        rules.push(Some(Rule {
+            mask: CrushRuleMask {
+                ruleset: 1,
+                rule_type: RuleType::Erasure,
+                min_size: 3,
+                max_size: 20,
+            },
+            steps: vec![step(OpCode::SetChooseLeafTries, 5, 0),
+                        step(OpCode::SetChooseTries, 100, 0),
+                        step(OpCode::Take, root_id, 0),
+                        step(OpCode::ChooseLeafIndep, 0, domain_id),
+                        step(OpCode::Emit, 0, 0)],
+        }));
+        rule_name_map.push((1, format!("{}erasure_ruleset", name_prefix)));
+    }
+
+    Ok((rules, rule_name_map))
+}
+
+// Adds our rules to a map that already has rules of its own, which pools may be using.
+//
+// The existing rules are never touched. Our rules get the next free ruleset ids and are named
+// with a "dct_" prefix, so a later run finds and replaces them instead of adding more copies.
+pub fn merge_rules(map: &mut CrushMap,
+                   root_id: i32,
+                   failure_domain: &str,
+                   erasure_coded: bool)
+                   -> Result<(), String> {
+    let (new_rules, new_names) = try!(generate_rules(root_id,
+                                                     failure_domain,
+                                                     &map.type_map,
+                                                     erasure_coded,
+                                                     "dct_"));
+
+    for (rule, (_, name)) in new_rules.into_iter().zip(new_names) {
+        let mut rule = match rule {
+            Some(rule) => rule,
+            None => continue,
+        };
+        let existing = map.rule_name_map
+            .iter()
+            .find(|(_, existing_name)| *existing_name == name)
+            .map(|&(id, _)| id as usize);
+
+        match existing {
+            Some(id) if id < map.rules.len() => {
+                if let Some(ref old_rule) = map.rules[id] {
+                    rule.mask.ruleset = old_rule.mask.ruleset;
+                }
+                map.rules[id] = Some(rule);
+            }
+            _ => {
+                // Rule ids and rulesets are kept equal, padding with empty rules if needed
+                let max_ruleset = map.rules
+                    .iter()
+                    .filter_map(|r| r.as_ref().map(|r| r.mask.ruleset as usize + 1))
+                    .max()
+                    .unwrap_or(0);
+                let id = if max_ruleset > map.rules.len() {
+                    max_ruleset
+                } else {
+                    map.rules.len()
+                };
+                if id > 255 {
+                    return Err("No free ruleset ids left in the current crushmap".to_string());
+                }
+                while map.rules.len() < id {
+                    map.rules.push(None);
+                }
+                rule.mask.ruleset = id as u8;
+                map.rules.push(Some(rule));
+                map.rule_name_map.push((id as i32, name));
+            }
+        }
+    }
+    map.max_rules = map.rules.len() as u32;
+    Ok(())
+}
+
+// Looks up the id of a bucket type such as "rack" or "host" in the map's type list
+pub fn type_id(name: &str, type_map: &[(i32, String)]) -> Result<i32, String> {
+    match type_map.iter().find(|(_, type_name)| type_name == name) {
+        Some(&(id, _)) => Ok(id),
+        None => Err(format!("Unknown bucket type: {}", name)),
+    }
+}
+
+fn step(op: OpCode, arg1: i32, arg2: i32) -> CrushRuleStep {
+    CrushRuleStep {
+        op: op,
+        arg1: (arg1, None),
+        arg2: (arg2, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use text;
+
+    fn type_map() -> Vec<(i32, String)> {
+        vec![(0, "osd".to_string()), (1, "host".to_string()), (3, "rack".to_string())]
+    }
+
+    fn ops(rule: &Option<Rule>) -> Vec<(OpCode, i32, i32)> {
+        rule.as_ref()
+            .unwrap()
+            .steps
+            .iter()
+            .map(|step| (step.op.clone(), step.arg1.0, step.arg2.0))
+            .collect()
+    }
+
+    #[test]
+    fn replicas_spread_across_the_failure_domain() {
+        let (rules, names) = generate_rules(-1, "rack", &type_map(), false, "").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(names, vec![(0, "replicated_ruleset".to_string())]);
+        assert_eq!(ops(&rules[0]),
+                   vec![(OpCode::Take, -1, 0),
+                        (OpCode::ChooseLeafFirstN, 0, 3),
+                        (OpCode::Emit, 0, 0)]);
+
+        let (rules, _) = generate_rules(-4, "host", &type_map(), false, "").unwrap();
+        assert_eq!(ops(&rules[0])[..2],
+                   [(OpCode::Take, -4, 0), (OpCode::ChooseLeafFirstN, 0, 1)]);
+    }
+
+    #[test]
+    fn erasure_coded_rule_is_optional() {
+        let (rules, names) = generate_rules(-1, "rack", &type_map(), true, "dct_").unwrap();
+        assert_eq!(names,
+                   vec![(0, "dct_replicated_ruleset".to_string()),
+                        (1, "dct_erasure_ruleset".to_string())]);
+        let erasure = rules[1].as_ref().unwrap();
+        assert_eq!(erasure.mask.ruleset, 1);
+        assert_eq!(erasure.mask.rule_type, RuleType::Erasure);
+        assert_eq!(ops(&rules[1]),
+                   vec![(OpCode::SetChooseLeafTries, 5, 0),
+                        (OpCode::SetChooseTries, 100, 0),
+                        (OpCode::Take, -1, 0),
+                        (OpCode::ChooseLeafIndep, 0, 3),
+                        (OpCode::Emit, 0, 0)]);
+    }
+
+    #[test]
+    fn unknown_failure_domain_is_an_error() {
+        assert_eq!(generate_rules(-1, "row", &type_map(), true, "").err(),
+                   Some("Unknown bucket type: row".to_string()));
+    }
+
+    // A map with one rule of its own, ruleset 0 or wherever `ruleset` puts it
+    fn map_with_rule(ruleset: u8) -> CrushMap {
+        let mut map = text::parse("type 0 osd\ntype 3 rack\ntype 10 root\nroot default {\n\
+                                   \tid -1\n}\n")
+            .unwrap();
+        let (mut rules, _) = generate_rules(-1, "rack", &map.type_map, false, "").unwrap();
+        if let Some(ref mut rule) = rules[0] {
+            rule.mask.ruleset = ruleset;
+        }
+        map.rules = rules;
+        map.rule_name_map = vec![(0, "replicated_ruleset".to_string())];
+        map.max_rules = 1;
+        map
+    }
+
+    fn rulesets(map: &CrushMap) -> Vec<Option<u8>> {
+        map.rules.iter().map(|rule| rule.as_ref().map(|rule| rule.mask.ruleset)).collect()
+    }
+
+    #[test]
+    fn merged_rules_are_added_after_existing_ones() {
+        let mut map = map_with_rule(0);
+        merge_rules(&mut map, -1, "rack", true).unwrap();
+        assert_eq!(map.rule_name_map,
+                   vec![(0, "replicated_ruleset".to_string()),
+                        (1, "dct_replicated_ruleset".to_string()),
+                        (2, "dct_erasure_ruleset".to_string())]);
+        assert_eq!(rulesets(&map), vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(map.max_rules, 3);
+    }
+
+    #[test]
+    fn merged_rules_are_replaced_on_rerun() {
+        let mut map = map_with_rule(0);
+        merge_rules(&mut map, -1, "rack", false).unwrap();
+        merge_rules(&mut map, -2, "rack", false).unwrap();
+        assert_eq!(map.rule_name_map.len(), 2);
+        assert_eq!(rulesets(&map), vec![Some(0), Some(1)]);
+        let steps = &map.rules[1].as_ref().unwrap().steps;
+        assert_eq!(steps[0].arg1.0, -2);
+    }
+
+    #[test]
+    fn merged_rules_skip_used_rulesets() {
+        // Rule ids and rulesets are kept equal, so a rule sitting on ruleset 3 pushes ours past
+        // it and leaves empty rules in between
+        let mut map = map_with_rule(3);
+        merge_rules(&mut map, -1, "rack", false).unwrap();
+        assert_eq!(rulesets(&map), vec![Some(3), None, None, None, Some(4)]);
+        assert_eq!(map.rule_name_map[1], (4, "dct_replicated_ruleset".to_string()));
+        assert_eq!(map.max_rules, 5);
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::prelude::*;
+
+use cluster::Cluster;
+
+// When rediscovery-interval is set, the update-status hook starts a round of discovery every
+// time the interval passes, see discovery.rs, and records the round in the cluster's schedule
+// file:
+//
+//   epoch 4
+//   started 1476374400
+//
+// Once every node has finished that round the controller adds "checked 4" and then compares
+// what it found with the accepted topology, so each scheduled round is only compared once.
+pub struct Schedule {
+    // The last round started on schedule, 0 before the first one
+    pub epoch: u64,
+    // Unix time the round started
+    pub started: u64,
+    // The last round that was compared
+    pub checked: u64,
+}
+
+impl Schedule {
+    pub fn load(cluster: &Cluster) -> Option<Schedule> {
+        let mut text = String::new();
+        if File::open(cluster.path("schedule"))
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .is_err() {
+            return None;
+        }
+        let mut schedule = Schedule {
+            epoch: 0,
+            started: 0,
+            checked: 0,
+        };
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                continue;
+            }
+            let value = fields[1].parse::<u64>().unwrap_or(0);
+            match fields[0] {
+                "epoch" => schedule.epoch = value,
+                "started" => schedule.started = value,
+                "checked" => schedule.checked = value,
+                _ => {}
+            }
+        }
+        Some(schedule)
+    }
+
+    pub fn save(&self, cluster: &Cluster) -> Result<(), String> {
+        let text = format!("epoch {}\nstarted {}\nchecked {}\n",
+                           self.epoch,
+                           self.started,
+                           self.checked);
+        let mut file = try!(File::create(cluster.path("schedule")).map_err(|e| e.to_string()));
+        file.write_all(text.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    // A scheduled round that every node has finished but that hasn't been compared yet
+    pub fn needs_check(&self, progress_epoch: u64, complete: bool) -> bool {
+        self.epoch != 0 && self.epoch == progress_epoch && self.checked != self.epoch && complete
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(epoch: u64, checked: u64) -> Schedule {
+        Schedule {
+            epoch: epoch,
+            started: 1476374400,
+            checked: checked,
+        }
+    }
+
+    #[test]
+    fn finished_scheduled_rounds_need_checking() {
+        assert!(schedule(4, 3).needs_check(4, true));
+        assert!(!schedule(4, 3).needs_check(4, false));
+    }
+
+    #[test]
+    fn rounds_are_only_checked_once() {
+        assert!(!schedule(4, 4).needs_check(4, true));
+    }
+
+    #[test]
+    fn other_rounds_are_left_alone() {
+        // Nothing has run on schedule yet
+        assert!(!schedule(0, 0).needs_check(0, true));
+        assert!(!schedule(0, 0).needs_check(2, true));
+        // A round started by hand after the scheduled one
+        assert!(!schedule(4, 3).needs_check(5, true));
+    }
+}
@@ -0,0 +1,849 @@
+use crushtool::{BucketTypes, CrushMap, OpCode};
+use std::collections::HashMap;
+
+use buckets;
+
+// A port of Ceph's CRUSH mapper (crush/mapper.c and crush/hash.c) working on crushtool's decoded
+// structures, used to see where placement groups land under two different maps.
+//
+// Everything follows the C implementation except straw2, which takes its logarithm in floating
+// point rather than from Ceph's lookup tables. The odd draw may come out differently to Ceph but
+// both maps are simulated the same way, so the estimate of how much moves is unaffected.
+
+const ITEM_UNDEF: i32 = 0x7ffffffe;
+const ITEM_NONE: i32 = 0x7fffffff;
+const HASH_SEED: u32 = 1315423911;
+
+// How many sampled placement groups would change OSDs going from one map to another
+pub struct Movement {
+    pub sampled: u32,
+    // Placement groups whose set of OSDs differs between the maps
+    pub changed: u32,
+    // Individual replicas that land on a different OSD
+    pub moved_replicas: u32,
+    pub total_replicas: u32,
+    // Placement groups the new map can't find enough OSDs for
+    pub incomplete: u32,
+}
+
+impl Movement {
+    pub fn changed_fraction(&self) -> f64 {
+        if self.sampled == 0 {
+            return 0.0;
+        }
+        self.changed as f64 / self.sampled as f64
+    }
+
+    pub fn replica_fraction(&self) -> f64 {
+        if self.total_replicas == 0 {
+            return 0.0;
+        }
+        self.moved_replicas as f64 / self.total_replicas as f64
+    }
+}
+
+// Maps `samples` placement groups through the given ruleset of both maps and counts how many of
+// them end up on a different set of OSDs.
+pub fn estimate_movement(old_map: &CrushMap,
+                         new_map: &CrushMap,
+                         ruleset: u8,
+                         replicas: usize,
+                         samples: u32)
+                         -> Result<Movement, String> {
+    let old_mapper = try!(Mapper::new(old_map, ruleset, replicas));
+    let new_mapper = try!(Mapper::new(new_map, ruleset, replicas));
+
+    let mut movement = Movement {
+        sampled: samples,
+        changed: 0,
+        moved_replicas: 0,
+        total_replicas: 0,
+        incomplete: 0,
+    };
+
+    for pg in 0..samples {
+        // Placement groups are fed to CRUSH as a hash of their seed and pool
+        let x = hash32_2(pg, 0);
+        let old_osds = old_mapper.map(x);
+        let new_osds = new_mapper.map(x);
+
+        if new_osds.len() < replicas {
+            movement.incomplete += 1;
+        }
+        let moved = old_osds.iter().filter(|osd| !new_osds.contains(osd)).count() as u32;
+        if moved > 0 || old_osds.len() != new_osds.len() {
+            movement.changed += 1;
+        }
+        movement.moved_replicas += moved;
+        movement.total_replicas += old_osds.len() as u32;
+    }
+    Ok(movement)
+}
+
+// The settings of one choose step, as crush_choose_firstn and crush_choose_indep take them
+#[derive(Clone, Copy)]
+struct Choice {
+    x: u32,
+    numrep: i32,
+    item_type: i32,
+    tries: u32,
+    recurse_tries: u32,
+    local_retries: u32,
+    local_fallback_retries: u32,
+    recurse_to_leaf: bool,
+    vary_r: u32,
+    stable: bool,
+    parent_r: i32,
+}
+
+struct Mapper<'a> {
+    map: &'a CrushMap,
+    buckets: HashMap<i32, &'a BucketTypes>,
+    rule: usize,
+    result_max: usize,
+}
+
+impl<'a> Mapper<'a> {
+    fn new(map: &'a CrushMap, ruleset: u8, replicas: usize) -> Result<Mapper<'a>, String> {
+        let rule = map.rules.iter().position(|rule| match *rule {
+            Some(ref rule) => {
+                rule.mask.ruleset == ruleset && rule.mask.min_size as usize <= replicas &&
+                rule.mask.max_size as usize >= replicas
+            }
+            None => false,
+        });
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                return Err(format!("No rule for ruleset {} with {} replicas", ruleset, replicas))
+            }
+        };
+        let mut by_id: HashMap<i32, &'a BucketTypes> = HashMap::new();
+        for bucket in &map.buckets {
+            if let Some(id) = buckets::id(bucket) {
+                by_id.insert(id, bucket);
+            }
+        }
+        Ok(Mapper {
+            map: map,
+            buckets: by_id,
+            rule: rule,
+            result_max: replicas,
+        })
+    }
+
+    // The OSDs placement input x maps to, as crush_do_rule computes them
+    fn map(&self, x: u32) -> Vec<i32> {
+        let map = self.map;
+        let rule = map.rules[self.rule].as_ref().unwrap();
+        let result_max = self.result_max;
+
+        let mut result: Vec<i32> = Vec::new();
+        let mut w: Vec<i32> = Vec::new();
+
+        let mut choose_tries: u32 = map.choose_total_tries.unwrap_or(19) + 1;
+        let mut choose_leaf_tries: u32 = 0;
+        let mut local_retries: u32 = map.choose_local_tries.unwrap_or(2);
+        let mut local_fallback_retries: u32 = map.choose_local_fallback_tries.unwrap_or(5);
+        let mut vary_r: u32 = map.chooseleaf_vary_r.unwrap_or(0) as u32;
+        let stable: bool = map.chooseleaf_stable.unwrap_or(0) != 0;
+        let descend_once: bool = map.chooseleaf_descend_once.unwrap_or(0) != 0;
+
+        for step in &rule.steps {
+            let arg1 = step.arg1.0;
+            let arg2 = step.arg2.0;
+            match step.op {
+                OpCode::Take => {
+                    if (arg1 >= 0 && arg1 < map.max_devices) || self.buckets.contains_key(&arg1) {
+                        w = vec![arg1];
+                    }
+                }
+                OpCode::SetChooseTries => {
+                    if arg1 > 0 {
+                        choose_tries = arg1 as u32;
+                    }
+                }
+                OpCode::SetChooseLeafTries => {
+                    if arg1 > 0 {
+                        choose_leaf_tries = arg1 as u32;
+                    }
+                }
+                OpCode::SetChooseLocalTries => {
+                    if arg1 >= 0 {
+                        local_retries = arg1 as u32;
+                    }
+                }
+                OpCode::SetChooseLocalFallbackTries => {
+                    if arg1 >= 0 {
+                        local_fallback_retries = arg1 as u32;
+                    }
+                }
+                OpCode::SetChooseLeafVaryR => {
+                    if arg1 >= 0 {
+                        vary_r = arg1 as u32;
+                    }
+                }
+                OpCode::ChooseFirstN |
+                OpCode::ChooseLeafFirstN |
+                OpCode::ChooseIndep |
+                OpCode::ChooseLeafIndep => {
+                    if w.is_empty() {
+                        continue;
+                    }
+                    let firstn = step.op == OpCode::ChooseFirstN ||
+                                 step.op == OpCode::ChooseLeafFirstN;
+                    let recurse_to_leaf = step.op == OpCode::ChooseLeafFirstN ||
+                                          step.op == OpCode::ChooseLeafIndep;
+                    let mut o: Vec<i32> = vec![0; result_max];
+                    let mut c: Vec<i32> = vec![0; result_max];
+                    let mut osize: usize = 0;
+
+                    for &item in &w {
+                        let mut numrep = arg1;
+                        if numrep <= 0 {
+                            numrep += result_max as i32;
+                            if numrep <= 0 {
+                                continue;
+                            }
+                        }
+                        let bucket = match self.buckets.get(&item) {
+                            Some(bucket) => *bucket,
+                            None => continue,
+                        };
+                        let mut choice = Choice {
+                            x: x,
+                            numrep: numrep,
+                            item_type: arg2,
+                            tries: choose_tries,
+                            recurse_tries: 0,
+                            local_retries: local_retries,
+                            local_fallback_retries: local_fallback_retries,
+                            recurse_to_leaf: recurse_to_leaf,
+                            vary_r: vary_r,
+                            stable: stable,
+                            parent_r: 0,
+                        };
+                        if firstn {
+                            choice.recurse_tries = if choose_leaf_tries > 0 {
+                                choose_leaf_tries
+                            } else if descend_once {
+                                1
+                            } else {
+                                choose_tries
+                            };
+                            let count = result_max - osize;
+                            osize += self.choose_firstn(bucket,
+                                                        &choice,
+                                                        &mut o[osize..],
+                                                        0,
+                                                        count,
+                                                        Some(&mut c[osize..]));
+                        } else {
+                            let out_size = if (numrep as usize) < result_max - osize {
+                                numrep as usize
+                            } else {
+                                result_max - osize
+                            };
+                            choice.recurse_tries = if choose_leaf_tries > 0 {
+                                choose_leaf_tries
+                            } else {
+                                1
+                            };
+                            self.choose_indep(bucket,
+                                              &choice,
+                                              &mut o[osize..],
+                                              0,
+                                              out_size,
+                                              Some(&mut c[osize..]));
+                            osize += out_size;
+                        }
+                    }
+                    if recurse_to_leaf {
+                        o = c;
+                    }
+                    o.truncate(osize);
+                    w = o;
+                }
+                OpCode::Emit => {
+                    for &item in &w {
+                        if result.len() < result_max {
+                            result.push(item);
+                        }
+                    }
+                    w = Vec::new();
+                }
+                OpCode::Noop => {}
+            }
+        }
+        result.into_iter().filter(|&item| item != ITEM_NONE).collect()
+    }
+
+    fn item_type(&self, item: i32) -> i32 {
+        if item >= 0 {
+            return 0;
+        }
+        match self.buckets.get(&item).and_then(|bucket| buckets::header(bucket)) {
+            Some(header) => header.bucket_type.clone() as i32,
+            None => -1,
+        }
+    }
+
+    fn choose_firstn(&self,
+                     bucket: &BucketTypes,
+                     choice: &Choice,
+                     out: &mut [i32],
+                     mut outpos: usize,
+                     out_size: usize,
+                     mut out2: Option<&mut [i32]>)
+                     -> usize {
+        let Choice { x, numrep, item_type, tries, local_retries, local_fallback_retries,
+                     recurse_to_leaf, vary_r, stable, .. } = *choice;
+        let mut count = out_size;
+        let mut rep: i32 = if stable { 0 } else { outpos as i32 };
+
+        while rep < numrep && count > 0 {
+            let mut ftotal: u32 = 0;
+            let mut skip_rep = false;
+            let mut item: i32 = 0;
+            loop {
+                let mut retry_descent = false;
+                let mut current = bucket;
+                let mut flocal: u32 = 0;
+                loop {
+                    let mut retry_bucket = false;
+                    let mut collide = false;
+                    let mut reject;
+                    let r = rep + choice.parent_r + ftotal as i32;
+                    let size = buckets::header(current).map(|h| h.size).unwrap_or(0);
+
+                    if size == 0 {
+                        reject = true;
+                    } else {
+                        item = if local_fallback_retries > 0 && flocal >= (size >> 1) &&
+                                  flocal > local_fallback_retries {
+                            perm_choose(current, x, r)
+                        } else {
+                            bucket_choose(current, x, r)
+                        };
+                        if item >= self.map.max_devices {
+                            skip_rep = true;
+                            break;
+                        }
+                        let itemtype = self.item_type(item);
+                        if itemtype != item_type {
+                            match self.buckets.get(&item) {
+                                Some(next) if item < 0 => {
+                                    current = *next;
+                                    continue;
+                                }
+                                _ => {
+                                    skip_rep = true;
+                                    break;
+                                }
+                            }
+                        }
+                        collide = out[..outpos].contains(&item);
+                        reject = false;
+                        if !collide && recurse_to_leaf {
+                            if item < 0 {
+                                let sub_r = if vary_r > 0 { r >> (vary_r - 1) } else { 0 };
+                                let leaf_reps = if stable { 1 } else { outpos as i32 + 1 };
+                                let leaf = Choice {
+                                    numrep: leaf_reps,
+                                    item_type: 0,
+                                    tries: choice.recurse_tries,
+                                    recurse_tries: 0,
+                                    recurse_to_leaf: false,
+                                    parent_r: sub_r,
+                                    ..*choice
+                                };
+                                let leaf_out = out2.as_mut().unwrap();
+                                let got = self.choose_firstn(self.buckets[&item],
+                                                             &leaf,
+                                                             leaf_out,
+                                                             outpos,
+                                                             count,
+                                                             None);
+                                if got <= outpos {
+                                    reject = true;
+                                }
+                            } else {
+                                out2.as_mut().unwrap()[outpos] = item;
+                            }
+                        }
+                    }
+                    if reject || collide {
+                        ftotal += 1;
+                        flocal += 1;
+                        if (collide && flocal <= local_retries) ||
+                           (local_fallback_retries > 0 &&
+                            flocal <= size + local_fallback_retries) {
+                            retry_bucket = true;
+                        } else if ftotal < tries {
+                            retry_descent = true;
+                        } else {
+                            skip_rep = true;
+                        }
+                    }
+                    if !retry_bucket {
+                        break;
+                    }
+                }
+                if !retry_descent {
+                    break;
+                }
+            }
+            if !skip_rep {
+                out[outpos] = item;
+                outpos += 1;
+                count -= 1;
+            }
+            rep += 1;
+        }
+        outpos
+    }
+
+    fn choose_indep(&self,
+                    bucket: &BucketTypes,
+                    choice: &Choice,
+                    out: &mut [i32],
+                    outpos: usize,
+                    left: usize,
+                    mut out2: Option<&mut [i32]>) {
+        let Choice { x, numrep, item_type, tries, recurse_to_leaf, parent_r, .. } = *choice;
+        let mut left = left;
+        let endpos = outpos + left;
+
+        for rep in outpos..endpos {
+            out[rep] = ITEM_UNDEF;
+            if let Some(ref mut out2) = out2 {
+                out2[rep] = ITEM_UNDEF;
+            }
+        }
+
+        let mut ftotal: u32 = 0;
+        while left > 0 && ftotal < tries {
+            for rep in outpos..endpos {
+                if out[rep] != ITEM_UNDEF {
+                    continue;
+                }
+                let mut current = bucket;
+                while let Some(header) = buckets::header(current) {
+                    let mut r = rep as i32 + parent_r;
+                    if let BucketTypes::Uniform(_) = *current {
+                        if header.size % numrep as u32 == 0 {
+                            r += (numrep + 1) * ftotal as i32;
+                        } else {
+                            r += numrep * ftotal as i32;
+                        }
+                    } else {
+                        r += numrep * ftotal as i32;
+                    }
+                    if header.size == 0 {
+                        break;
+                    }
+                    let item = bucket_choose(current, x, r);
+                    if item >= self.map.max_devices {
+                        out[rep] = ITEM_NONE;
+                        if let Some(ref mut out2) = out2 {
+                            out2[rep] = ITEM_NONE;
+                        }
+                        left -= 1;
+                        break;
+                    }
+                    let itemtype = self.item_type(item);
+                    if itemtype != item_type {
+                        match self.buckets.get(&item) {
+                            Some(next) if item < 0 => {
+                                current = *next;
+                                continue;
+                            }
+                            _ => {
+                                out[rep] = ITEM_NONE;
+                                if let Some(ref mut out2) = out2 {
+                                    out2[rep] = ITEM_NONE;
+                                }
+                                left -= 1;
+                                break;
+                            }
+                        }
+                    }
+                    if out[outpos..endpos].contains(&item) {
+                        break;
+                    }
+                    if recurse_to_leaf {
+                        let leaf_out = out2.as_mut().unwrap();
+                        if item < 0 {
+                            let leaf = Choice {
+                                item_type: 0,
+                                tries: choice.recurse_tries,
+                                recurse_tries: 0,
+                                recurse_to_leaf: false,
+                                parent_r: r,
+                                ..*choice
+                            };
+                            self.choose_indep(self.buckets[&item], &leaf, leaf_out, rep, 1, None);
+                            if leaf_out[rep] == ITEM_NONE {
+                                break;
+                            }
+                        } else {
+                            leaf_out[rep] = item;
+                        }
+                    }
+                    out[rep] = item;
+                    left -= 1;
+                    break;
+                }
+            }
+            ftotal += 1;
+        }
+        for rep in outpos..endpos {
+            if out[rep] == ITEM_UNDEF {
+                out[rep] = ITEM_NONE;
+            }
+            if let Some(ref mut out2) = out2 {
+                if out2[rep] == ITEM_UNDEF {
+                    out2[rep] = ITEM_NONE;
+                }
+            }
+        }
+    }
+}
+
+fn bucket_choose(bucket: &BucketTypes, x: u32, r: i32) -> i32 {
+    let r = r as u32;
+    match *bucket {
+        BucketTypes::Uniform(_) => perm_choose(bucket, x, r as i32),
+        BucketTypes::List(ref list) => {
+            let header = &list.bucket;
+            for i in (0..header.items.len()).rev() {
+                let item = header.items[i].0;
+                let mut w: u64 = hash32_4(x, item as u32, r, header.id as u32) as u64;
+                w &= 0xffff;
+                w *= list.item_weights[i].1 as u64;
+                w >>= 16;
+                if w < list.item_weights[i].0 as u64 {
+                    return item;
+                }
+            }
+            header.items[0].0
+        }
+        BucketTypes::Tree(ref tree) => {
+            let header = &tree.bucket;
+            let mut n: u32 = (tree.num_nodes >> 1) as u32;
+            while (n & 1) == 0 {
+                let w = *tree.node_weights.get(n as usize).unwrap_or(&0) as u64;
+                let t = (hash32_4(x, n, r, header.id as u32) as u64 * w) >> 32;
+                let h = n.trailing_zeros();
+                let left = n - (1 << (h - 1));
+                if t < *tree.node_weights.get(left as usize).unwrap_or(&0) as u64 {
+                    n = left;
+                } else {
+                    n += 1 << (h - 1);
+                }
+            }
+            header.items[(n >> 1) as usize].0
+        }
+        BucketTypes::Straw(ref straw) => {
+            let header = &straw.bucket;
+            let mut high: usize = 0;
+            let mut high_draw: u64 = 0;
+            for (i, &(item, _)) in header.items.iter().enumerate() {
+                let mut draw: u64 = (hash32_3(x, item as u32, r) & 0xffff) as u64;
+                draw *= straw.item_weights[i].1 as u64;
+                if i == 0 || draw > high_draw {
+                    high = i;
+                    high_draw = draw;
+                }
+            }
+            header.items[high].0
+        }
+        BucketTypes::Straw2(ref straw2) => {
+            let header = &straw2.bucket;
+            let mut high: usize = 0;
+            let mut high_draw: i64 = 0;
+            for (i, &(item, _)) in header.items.iter().enumerate() {
+                let weight = straw2.item_weights[i];
+                let draw: i64 = if weight > 0 {
+                    let u = (hash32_3(x, item as u32, r) & 0xffff) as f64;
+                    // crush_ln(u) is 2^44 * log2(u + 1)
+                    let ln = ((u + 1.0).log2() * 17592186044416.0) as i64 - 0x1000000000000;
+                    ln / weight as i64
+                } else {
+                    i64::MIN
+                };
+                if i == 0 || draw > high_draw {
+                    high = i;
+                    high_draw = draw;
+                }
+            }
+            header.items[high].0
+        }
+        BucketTypes::Unknown => ITEM_NONE,
+    }
+}
+
+// The permutation uniform buckets use, worked out from scratch for each r rather than cached
+fn perm_choose(bucket: &BucketTypes, x: u32, r: i32) -> i32 {
+    let header = buckets::header(bucket).unwrap();
+    let size = header.size as usize;
+    let pr = (r as u32 as usize) % size;
+    let mut perm: Vec<usize> = (0..size).collect();
+    for p in 0..pr + 1 {
+        if p < size - 1 {
+            let i = hash32_3(x, header.id as u32, p as u32) as usize % (size - p);
+            perm.swap(p, p + i);
+        }
+    }
+    header.items[perm[pr]].0
+}
+
+fn hashmix(a: &mut u32, b: &mut u32, c: &mut u32) {
+    *a = a.wrapping_sub(*b);
+    *a = a.wrapping_sub(*c);
+    *a ^= *c >> 13;
+    *b = b.wrapping_sub(*c);
+    *b = b.wrapping_sub(*a);
+    *b ^= *a << 8;
+    *c = c.wrapping_sub(*a);
+    *c = c.wrapping_sub(*b);
+    *c ^= *b >> 13;
+    *a = a.wrapping_sub(*b);
+    *a = a.wrapping_sub(*c);
+    *a ^= *c >> 12;
+    *b = b.wrapping_sub(*c);
+    *b = b.wrapping_sub(*a);
+    *b ^= *a << 16;
+    *c = c.wrapping_sub(*a);
+    *c = c.wrapping_sub(*b);
+    *c ^= *b >> 5;
+    *a = a.wrapping_sub(*b);
+    *a = a.wrapping_sub(*c);
+    *a ^= *c >> 3;
+    *b = b.wrapping_sub(*c);
+    *b = b.wrapping_sub(*a);
+    *b ^= *a << 10;
+    *c = c.wrapping_sub(*a);
+    *c = c.wrapping_sub(*b);
+    *c ^= *b >> 15;
+}
+
+fn hash32_2(a: u32, b: u32) -> u32 {
+    let (mut a, mut b) = (a, b);
+    let mut hash = HASH_SEED ^ a ^ b;
+    let mut x: u32 = 231232;
+    let mut y: u32 = 1232;
+    hashmix(&mut a, &mut b, &mut hash);
+    hashmix(&mut x, &mut a, &mut hash);
+    hashmix(&mut b, &mut y, &mut hash);
+    hash
+}
+
+fn hash32_3(a: u32, b: u32, c: u32) -> u32 {
+    let (mut a, mut b, mut c) = (a, b, c);
+    let mut hash = HASH_SEED ^ a ^ b ^ c;
+    let mut x: u32 = 231232;
+    let mut y: u32 = 1232;
+    hashmix(&mut a, &mut b, &mut hash);
+    hashmix(&mut c, &mut x, &mut hash);
+    hashmix(&mut y, &mut a, &mut hash);
+    hashmix(&mut b, &mut x, &mut hash);
+    hashmix(&mut y, &mut c, &mut hash);
+    hash
+}
+
+fn hash32_4(a: u32, b: u32, c: u32, d: u32) -> u32 {
+    let (mut a, mut b, mut c, mut d) = (a, b, c, d);
+    let mut hash = HASH_SEED ^ a ^ b ^ c ^ d;
+    let mut x: u32 = 231232;
+    let mut y: u32 = 1232;
+    hashmix(&mut a, &mut b, &mut hash);
+    hashmix(&mut c, &mut d, &mut hash);
+    hashmix(&mut a, &mut x, &mut hash);
+    hashmix(&mut y, &mut b, &mut hash);
+    hashmix(&mut c, &mut x, &mut hash);
+    hashmix(&mut y, &mut d, &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crushtool::CrushMap;
+    use text;
+
+    // No crushtool here to compare placements with, so these check what every CRUSH placement
+    // has to satisfy: replicas on distinct hosts, weights respected, and straw2 only moving data
+    // onto a new host.
+
+    const JEWEL_TUNABLES: &str = "tunable choose_local_tries 0
+tunable choose_local_fallback_tries 0
+tunable choose_total_tries 50
+tunable chooseleaf_descend_once 1
+tunable chooseleaf_vary_r 1
+tunable chooseleaf_stable 1
+tunable straw_calc_version 1
+tunable allowed_bucket_algs 54
+";
+
+    // A map with one host per entry of `hosts`, each holding OSDs of the given weights, under a
+    // root of buckets using `alg`
+    fn build(alg: &str, hosts: &[&[f64]], rule_mode: &str) -> CrushMap {
+        let mut out = String::from(JEWEL_TUNABLES);
+        let mut osd = 0;
+        let mut host_lines = String::new();
+        let mut root_items = String::new();
+        for (h, weights) in hosts.iter().enumerate() {
+            host_lines.push_str(&format!("host node{} {{\n\tid -{}\n\talg {}\n", h, h + 2, alg));
+            for weight in weights.iter() {
+                out.push_str(&format!("device {} osd.{}\n", osd, osd));
+                host_lines.push_str(&format!("\titem osd.{} weight {:.3}\n", osd, weight));
+                osd += 1;
+            }
+            host_lines.push_str("}\n");
+            let host_weight: f64 = weights.iter().sum();
+            root_items.push_str(&format!("\titem node{} weight {:.3}\n", h, host_weight));
+        }
+        out.push_str("type 0 osd\ntype 1 host\ntype 10 root\n");
+        out.push_str(&host_lines);
+        out.push_str(&format!("root default {{\n\tid -1\n\talg {}\n{}}}\n", alg, root_items));
+        out.push_str(&format!("rule data {{\n\truleset 0\n\ttype replicated\n\tmin_size 1\n\t\
+                               max_size 10\n\tstep take default\n\tstep chooseleaf {} 0 type \
+                               host\n\tstep emit\n}}\n",
+                              rule_mode));
+        text::parse(&out).unwrap()
+    }
+
+    // The host an OSD sits on, from the order build() numbers them in
+    fn host_of(hosts: &[&[f64]], osd: i32) -> usize {
+        let mut first = 0;
+        for (h, weights) in hosts.iter().enumerate() {
+            if (osd as usize) < first + weights.len() {
+                return h;
+            }
+            first += weights.len();
+        }
+        panic!("osd.{} is not in the map", osd);
+    }
+
+    #[test]
+    fn replicas_land_on_distinct_hosts() {
+        let uneven: &[&[f64]] = &[&[1.0, 1.0], &[1.0, 1.0], &[1.0, 2.0], &[2.0]];
+        // Uniform buckets need every item to weigh the same
+        let even: &[&[f64]] = &[&[1.0, 1.0], &[1.0, 1.0], &[1.0, 1.0], &[1.0, 1.0]];
+        for alg in &["straw2", "straw", "list", "tree", "uniform"] {
+            let hosts = if *alg == "uniform" { even } else { uneven };
+            for mode in &["firstn", "indep"] {
+                let map = build(alg, hosts, mode);
+                let mapper = Mapper::new(&map, 0, 3).unwrap();
+                for pg in 0..500 {
+                    let x = hash32_2(pg, 0);
+                    let osds = mapper.map(x);
+                    assert_eq!(osds.len(), 3, "{} {} pg {}: {:?}", alg, mode, pg, osds);
+                    assert_eq!(osds, mapper.map(x));
+                    let mut used: Vec<usize> = osds.iter()
+                        .map(|&osd| host_of(hosts, osd))
+                        .collect();
+                    used.sort();
+                    used.dedup();
+                    assert_eq!(used.len(), 3, "{} {} pg {}: {:?}", alg, mode, pg, osds);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn as_many_replicas_as_hosts_uses_every_host() {
+        let hosts: &[&[f64]] = &[&[1.0], &[1.0], &[1.0]];
+        let map = build("straw2", hosts, "firstn");
+        let mapper = Mapper::new(&map, 0, 3).unwrap();
+        for pg in 0..200 {
+            let mut osds = mapper.map(hash32_2(pg, 0));
+            osds.sort();
+            assert_eq!(osds, vec![0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn zero_weight_osds_are_never_chosen() {
+        let hosts: &[&[f64]] = &[&[1.0, 0.0], &[1.0, 1.0], &[0.0, 1.0]];
+        let map = build("straw2", hosts, "firstn");
+        let mapper = Mapper::new(&map, 0, 2).unwrap();
+        for pg in 0..1000 {
+            let osds = mapper.map(hash32_2(pg, 0));
+            assert_eq!(osds.len(), 2);
+            assert!(!osds.contains(&1) && !osds.contains(&4), "pg {}: {:?}", pg, osds);
+        }
+    }
+
+    #[test]
+    fn placement_follows_weight() {
+        let hosts: &[&[f64]] = &[&[1.0], &[3.0]];
+        for alg in &["straw2", "straw", "list", "tree"] {
+            let map = build(alg, hosts, "firstn");
+            let mapper = Mapper::new(&map, 0, 1).unwrap();
+            let mut counts = [0u32; 2];
+            for pg in 0..20000 {
+                let osds = mapper.map(hash32_2(pg, 0));
+                counts[osds[0] as usize] += 1;
+            }
+            let ratio = counts[1] as f64 / counts[0] as f64;
+            assert!(ratio > 2.6 && ratio < 3.4, "{}: {:?}", alg, counts);
+        }
+    }
+
+    #[test]
+    fn identical_maps_move_nothing() {
+        let hosts: &[&[f64]] = &[&[1.0, 1.0], &[1.0, 1.0], &[1.0, 1.0]];
+        let map = build("straw2", hosts, "firstn");
+        let movement = estimate_movement(&map, &map, 0, 3, 1000).unwrap();
+        assert_eq!(movement.changed, 0);
+        assert_eq!(movement.moved_replicas, 0);
+        assert_eq!(movement.incomplete, 0);
+        assert_eq!(movement.total_replicas, 3000);
+        assert_eq!(movement.changed_fraction(), 0.0);
+    }
+
+    #[test]
+    fn straw2_only_moves_data_onto_a_new_host() {
+        let old_hosts: &[&[f64]] = &[&[1.0], &[1.0], &[1.0], &[1.0]];
+        let new_hosts: &[&[f64]] = &[&[1.0], &[1.0], &[1.0], &[1.0], &[1.0]];
+        let old_map = build("straw2", old_hosts, "firstn");
+        let new_map = build("straw2", new_hosts, "firstn");
+        let old_mapper = Mapper::new(&old_map, 0, 1).unwrap();
+        let new_mapper = Mapper::new(&new_map, 0, 1).unwrap();
+        for pg in 0..2000 {
+            let x = hash32_2(pg, 0);
+            let (old_osds, new_osds) = (old_mapper.map(x), new_mapper.map(x));
+            if old_osds != new_osds {
+                assert_eq!(new_osds, vec![4], "pg {} moved from {:?}", pg, old_osds);
+            }
+        }
+
+        // About a fifth of the data belongs on the new host
+        let movement = estimate_movement(&old_map, &new_map, 0, 1, 2000).unwrap();
+        let moved = movement.replica_fraction();
+        assert!(moved > 0.15 && moved < 0.25, "{} moved", moved);
+    }
+
+    #[test]
+    fn losing_a_host_leaves_placements_incomplete() {
+        let old_hosts: &[&[f64]] = &[&[1.0], &[1.0], &[1.0]];
+        let new_hosts: &[&[f64]] = &[&[1.0], &[1.0], &[0.0]];
+        let old_map = build("straw2", old_hosts, "indep");
+        let new_map = build("straw2", new_hosts, "indep");
+        let movement = estimate_movement(&old_map, &new_map, 0, 3, 100).unwrap();
+        assert_eq!(movement.incomplete, 100);
+        assert_eq!(movement.changed, 100);
+        assert_eq!(movement.moved_replicas, 100);
+    }
+
+    #[test]
+    fn missing_rule_is_an_error() {
+        let hosts: &[&[f64]] = &[&[1.0], &[1.0]];
+        let map = build("straw2", hosts, "firstn");
+        assert!(estimate_movement(&map, &map, 1, 3, 10).is_err());
+        // The rule only covers pools of up to 10 replicas
+        assert!(estimate_movement(&map, &map, 0, 11, 10).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use juju;
+use log::LogLevel;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cluster::Cluster;
+
+// The controller keeps its files in a directory of its own, set by the state-dir config, rather
+// than in /tmp where they are shared with every user and lost on reboot. Each cluster gets a
+// directory under it holding the working files (currentmap, dct_crushmap, dct_crushmap.txt) and
+// a snapshots directory:
+//
+//   /var/lib/dct-controller/dct-node/currentmap
+//   /var/lib/dct-controller/dct-node/snapshots/baseline-1476374400
+//   /var/lib/dct-controller/dct-node/snapshots/neighbors-1476374700
+//   /var/lib/dct-controller/dct-node/snapshots/crushmap-1476374700
+//
+// Snapshots are named after their kind and the unix time they were taken. Another snapshot of
+// the same kind taken within the same second gets a counter, as in crushmap-1476374700-1. Only
+// the newest snapshot-retention of each kind are kept.
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/dct-controller";
+const DEFAULT_RETENTION: usize = 10;
+
+pub fn state_dir() -> PathBuf {
+    match juju::config_get("state-dir") {
+        Ok(ref dir) if !dir.trim().is_empty() => PathBuf::from(dir.trim()),
+        _ => PathBuf::from(DEFAULT_STATE_DIR),
+    }
+}
+
+// A directory under the state directory, created readable only by the charm if needed
+pub fn directory(name: &str) -> PathBuf {
+    let path = state_dir().join(name);
+    let _ = DirBuilder::new().recursive(true).mode(0o700).create(&path);
+    path
+}
+
+// Stores a new snapshot of the given kind for the cluster and drops the oldest ones beyond the
+// retention setting. Returns the snapshot's path.
+pub fn snapshot(cluster: &Cluster, kind: &str, bytes: &[u8]) -> Result<PathBuf, String> {
+    // The file is only created if nothing took its name in the meantime
+    loop {
+        let path = try!(snapshot_path(cluster, kind));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                try!(file.write_all(bytes).map_err(|e| e.to_string()));
+                prune(cluster, kind);
+                return Ok(path);
+            }
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+// Where a snapshot of the given kind taken now goes, for files written by other programs. Call
+// prune once it is written.
+pub fn snapshot_path(cluster: &Cluster, kind: &str) -> Result<PathBuf, String> {
+    let timestamp = try!(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string()));
+    let directory = snapshot_dir(cluster);
+    let mut path = directory.join(format!("{}-{}", kind, timestamp.as_secs()));
+    let mut counter = 1;
+    while path.exists() {
+        path = directory.join(format!("{}-{}-{}", kind, timestamp.as_secs(), counter));
+        counter += 1;
+    }
+    Ok(path)
+}
+
+// Every snapshot of a kind with the time it was taken, oldest first
+pub fn snapshots(cluster: &Cluster, kind: &str) -> Vec<(u64, PathBuf)> {
+    let prefix = format!("{}-", kind);
+    let entries = match fs::read_dir(snapshot_dir(cluster)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut snapshots: Vec<(u64, u64, PathBuf)> = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&prefix) {
+                return None;
+            }
+            let mut fields = name[prefix.len()..].splitn(2, '-');
+            let time = fields.next().and_then(|time| time.parse::<u64>().ok());
+            let counter = match fields.next() {
+                Some(counter) => counter.parse::<u64>().ok(),
+                None => Some(0),
+            };
+            match (time, counter) {
+                (Some(time), Some(counter)) => Some((time, counter, entry.path())),
+                _ => None,
+            }
+        })
+        .collect();
+    snapshots.sort();
+    snapshots.into_iter().map(|(time, _, path)| (time, path)).collect()
+}
+
+fn snapshot_dir(cluster: &Cluster) -> PathBuf {
+    directory(&format!("{}/snapshots", cluster.name))
+}
+
+pub fn prune(cluster: &Cluster, kind: &str) {
+    // 0 keeps every snapshot
+    let retention = match juju::config_get("snapshot-retention") {
+        Ok(value) => value.trim().parse::<usize>().unwrap_or(DEFAULT_RETENTION),
+        Err(_) => DEFAULT_RETENTION,
+    };
+    if retention == 0 {
+        return;
+    }
+    let snapshots = snapshots(cluster, kind);
+    if snapshots.len() <= retention {
+        return;
+    }
+    for (_, path) in &snapshots[..snapshots.len() - retention] {
+        if let Err(e) = fs::remove_file(path) {
+            juju::log(format!("Could not remove old snapshot {}: {}", path.display(), e),
+                      Some(LogLevel::Warn));
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+// Neighbor data is kept one host per line, followed by the hosts it saw
+pub fn neighbors_text(machines: &HashMap<String, Vec<String>>) -> String {
+    let mut hosts: Vec<&String> = machines.keys().collect();
+    hosts.sort();
+    let mut text = String::new();
+    for host in hosts {
+        let mut neighbors = machines[host].clone();
+        neighbors.sort();
+        text.push_str(&format!("{} {}\n", host, neighbors.join(" ")));
+    }
+    text
+}
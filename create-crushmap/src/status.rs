@@ -0,0 +1,61 @@
+use juju;
+
+use cluster;
+use topology;
+
+// Sets the controller's status. Drift found by a scheduled round of discovery keeps the status
+// blocked until the operator accepts a topology, by applying a crushmap or with
+// accept-topology, so progress and action messages can't hide it. Every status the controller
+// sets goes through here.
+pub fn set(status: juju::Status) {
+    let drifted: Vec<String> = cluster::all()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|cluster| {
+            topology::drift_flag(cluster)
+                .map(|changes| format!("{} ({} changes)", cluster.name, changes.len()))
+        })
+        .collect();
+    let _ = juju::status_set(with_drift(status, &drifted));
+}
+
+// The status to show given the clusters with drift flagged, each named with its change count
+fn with_drift(status: juju::Status, drifted: &[String]) -> juju::Status {
+    if drifted.is_empty() {
+        return status;
+    }
+    juju::Status {
+        status_type: juju::StatusType::Blocked,
+        message: format!("Topology drift detected on {}, see topology-history. Run \
+                          create-crushmap and apply-crushmap, or accept-topology, once reviewed",
+                         drifted.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiting() -> juju::Status {
+        juju::Status {
+            status_type: juju::StatusType::Waiting,
+            message: "1/2 nodes finished discovery (epoch 3)".to_string(),
+        }
+    }
+
+    #[test]
+    fn statuses_pass_through_without_drift() {
+        let status = with_drift(waiting(), &[]);
+        assert_eq!(status.status_type.to_string(), "waiting");
+        assert_eq!(status.message, "1/2 nodes finished discovery (epoch 3)");
+    }
+
+    #[test]
+    fn drift_blocks_the_status() {
+        let drifted = vec!["ceph-a (2 changes)".to_string(), "ceph-b (1 changes)".to_string()];
+        let status = with_drift(waiting(), &drifted);
+        assert_eq!(status.status_type.to_string(), "blocked");
+        assert!(status.message
+            .starts_with("Topology drift detected on ceph-a (2 changes), ceph-b (1 changes),"));
+    }
+}
@@ -0,0 +1,673 @@
+use crushtool::{BucketAlg, CrushMap, CrushRuleMask, CrushRuleStep, OpCode, Rule, RuleType};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use buckets;
+use diff;
+
+// Renders a crushmap in the text format `crushtool -d` produces, so the generated map can be
+// read and reviewed without a Ceph install. The output follows the Jewel decompiler: tunables
+// that differ from the legacy defaults, then devices, types, buckets and rules. Buckets are
+// written children first, since the compiler needs every item defined before it is used.
+pub fn render(map: &CrushMap) -> String {
+    let mut out = String::new();
+    out.push_str("# begin crush map\n");
+    for (name, value, legacy) in tunables(map) {
+        if let Some(value) = value {
+            if value != legacy {
+                out.push_str(&format!("tunable {} {}\n", name, value));
+            }
+        }
+    }
+
+    out.push_str("\n# devices\n");
+    for device in 0..map.max_devices {
+        out.push_str(&format!("device {} {}\n", device, item_name(map, device)));
+    }
+
+    out.push_str("\n# types\n");
+    for &(id, ref name) in &map.type_map {
+        out.push_str(&format!("type {} {}\n", id, name));
+    }
+
+    out.push_str("\n# buckets\n");
+    let mut written: HashSet<i32> = HashSet::new();
+    let mut bucket_ids: Vec<i32> = map.buckets.iter().filter_map(buckets::id).collect();
+    bucket_ids.sort_by(|a, b| b.cmp(a));
+    for id in bucket_ids {
+        render_bucket(map, id, &mut written, &mut out);
+    }
+
+    out.push_str("\n# rules\n");
+    for (index, rule) in map.rules.iter().enumerate() {
+        let rule = match *rule {
+            Some(ref rule) => rule,
+            None => continue,
+        };
+        let name = match map.rule_name_map.iter().find(|&&(id, _)| id == index as i32) {
+            Some((_, name)) => name.clone(),
+            None => String::new(),
+        };
+        out.push_str(&format!("rule {} {{\n", name));
+        out.push_str(&format!("\truleset {}\n", rule.mask.ruleset));
+        out.push_str(match rule.mask.rule_type {
+            RuleType::Replicated => "\ttype replicated\n",
+            RuleType::Raid4 => "\ttype 2\n",
+            RuleType::Erasure => "\ttype erasure\n",
+        });
+        out.push_str(&format!("\tmin_size {}\n", rule.mask.min_size));
+        out.push_str(&format!("\tmax_size {}\n", rule.mask.max_size));
+        for step in &rule.steps {
+            out.push_str(&format!("\tstep {}\n", diff::describe_step(map, step)));
+        }
+        out.push_str("}\n");
+    }
+
+    out.push_str("\n# end crush map\n");
+    out
+}
+
+fn render_bucket(map: &CrushMap, id: i32, written: &mut HashSet<i32>, out: &mut String) {
+    if written.contains(&id) {
+        return;
+    }
+    written.insert(id);
+    let bucket = match buckets::find(&map.buckets, id) {
+        Some(bucket) => bucket,
+        None => return,
+    };
+    let header = buckets::header(bucket).unwrap();
+    for &(item, _) in &header.items {
+        if item < 0 {
+            render_bucket(map, item, written, out);
+        }
+    }
+
+    out.push_str(&format!("{} {} {{\n",
+                          type_name(map, header.bucket_type.clone() as i32),
+                          item_name(map, id)));
+    out.push_str(&format!("\tid {}\t\t# do not change unnecessarily\n", id));
+    out.push_str(&format!("\t# weight {}\n", diff::format_weight(header.weight)));
+    let show_position = match header.alg {
+        BucketAlg::Uniform => {
+            out.push_str(&format!("\talg uniform\t# do not change bucket size ({}) \
+                                   unnecessarily\n",
+                                  header.items.len()));
+            true
+        }
+        BucketAlg::List => {
+            out.push_str("\talg list\t# add new items at the end; do not change order \
+                          unnecessarily\n");
+            false
+        }
+        BucketAlg::Tree => {
+            out.push_str("\talg tree\t# do not change pos for existing items unnecessarily\n");
+            true
+        }
+        BucketAlg::Straw => {
+            out.push_str("\talg straw\n");
+            false
+        }
+        BucketAlg::Straw2 => {
+            out.push_str("\talg straw2\n");
+            false
+        }
+    };
+    out.push_str(&format!("\thash {}\t# rjenkins1\n", header.hash.clone() as u8));
+    let weights = buckets::item_weights(bucket);
+    for (position, (&(item, _), weight)) in header.items.iter().zip(weights).enumerate() {
+        out.push_str(&format!("\titem {} weight {}",
+                              item_name(map, item),
+                              diff::format_weight(weight)));
+        if show_position {
+            out.push_str(&format!(" pos {}", position));
+        }
+        out.push('\n');
+    }
+    out.push_str("}\n");
+}
+
+// Each tunable with the value it has when a map doesn't set it
+fn tunables(map: &CrushMap) -> Vec<(&'static str, Option<u32>, u32)> {
+    vec![("choose_local_tries", map.choose_local_tries, 2),
+         ("choose_local_fallback_tries", map.choose_local_fallback_tries, 5),
+         ("choose_total_tries", map.choose_total_tries, 19),
+         ("chooseleaf_descend_once", map.chooseleaf_descend_once, 0),
+         ("chooseleaf_vary_r", map.chooseleaf_vary_r.map(|v| v as u32), 0),
+         ("chooseleaf_stable", map.chooseleaf_stable.map(|v| v as u32), 0),
+         ("straw_calc_version", map.straw_calc_version.map(|v| v as u32), 0),
+         // Uniform, list and straw
+         ("allowed_bucket_algs", map.allowed_bucket_algorithms, 22)]
+}
+
+// Names as crushtool prints them, falling back to deviceN and bucketN for unnamed items
+pub fn item_name(map: &CrushMap, id: i32) -> String {
+    match map.name_map.iter().find(|&&(index, _)| index == id) {
+        Some((_, name)) => name.clone(),
+        None if id >= 0 => format!("device{}", id),
+        None => format!("bucket{}", -1 - id),
+    }
+}
+
+pub fn type_name(map: &CrushMap, id: i32) -> String {
+    match map.type_map.iter().find(|&&(type_id, _)| type_id == id) {
+        Some((_, name)) => name.clone(),
+        None => format!("type{}", id),
+    }
+}
+
+// A bucket as written in the text, before item names are resolved to ids
+struct ParsedBucket {
+    type_id: i32,
+    name: String,
+    id: Option<i32>,
+    alg: BucketAlg,
+    items: Vec<(String, u32)>,
+}
+
+// Parses crushmap text in the `crushtool -d` format back into a crushmap, so an operator can
+// edit the rendered map and hand it back. Anything crushtool can't encode, such as device
+// classes or a hash other than rjenkins1, is an error rather than being dropped. Bucket ids that
+// are left out are given the next free id, and tunables that aren't mentioned keep their legacy
+// defaults, as they would with `crushtool -c`.
+pub fn parse(text: &str) -> Result<CrushMap, String> {
+    let mut tokens = Tokens::new(text);
+
+    let mut map = CrushMap {
+        magic: 65536,
+        max_buckets: 0,
+        max_rules: 0,
+        max_devices: 0,
+        buckets: Vec::new(),
+        rules: Vec::new(),
+        type_map: Vec::new(),
+        name_map: Vec::new(),
+        rule_name_map: Vec::new(),
+        choose_local_tries: Some(2),
+        choose_local_fallback_tries: Some(5),
+        choose_total_tries: Some(19),
+        chooseleaf_descend_once: Some(0),
+        chooseleaf_vary_r: Some(0),
+        straw_calc_version: Some(0),
+        allowed_bucket_algorithms: Some(22),
+        chooseleaf_stable: Some(0),
+    };
+    let mut parsed_buckets: Vec<ParsedBucket> = Vec::new();
+    let mut parsed_rules: Vec<(String, Vec<String>, usize)> = Vec::new();
+
+    while let Some(word) = tokens.next() {
+        match &word[..] {
+            "tunable" => {
+                let name = try!(tokens.word());
+                let value = try!(tokens.number::<u32>());
+                match &name[..] {
+                    "choose_local_tries" => map.choose_local_tries = Some(value),
+                    "choose_local_fallback_tries" => map.choose_local_fallback_tries = Some(value),
+                    "choose_total_tries" => map.choose_total_tries = Some(value),
+                    "chooseleaf_descend_once" => map.chooseleaf_descend_once = Some(value),
+                    "chooseleaf_vary_r" => map.chooseleaf_vary_r = Some(value as u8),
+                    "chooseleaf_stable" => map.chooseleaf_stable = Some(value as u8),
+                    "straw_calc_version" => map.straw_calc_version = Some(value as u8),
+                    "allowed_bucket_algs" => map.allowed_bucket_algorithms = Some(value),
+                    _ => return Err(tokens.error(&format!("unknown tunable {}", name))),
+                }
+            }
+            "device" => {
+                let id = try!(tokens.number::<i32>());
+                let name = try!(tokens.word());
+                if tokens.peek() == Some("class") {
+                    return Err(tokens.error("device classes are not supported"));
+                }
+                if id < 0 {
+                    return Err(tokens.error(&format!("device {} has a negative id", name)));
+                }
+                // Unnamed devices are written as deviceN and are only placeholders
+                if name != format!("device{}", id) {
+                    map.name_map.push((id, name));
+                }
+                if id + 1 > map.max_devices {
+                    map.max_devices = id + 1;
+                }
+            }
+            "type" => {
+                let id = try!(tokens.number::<i32>());
+                let name = try!(tokens.word());
+                map.type_map.push((id, name));
+            }
+            "rule" => {
+                let name = if tokens.peek() == Some("{") {
+                    String::new()
+                } else {
+                    try!(tokens.word())
+                };
+                try!(tokens.expect("{"));
+                let line = tokens.line();
+                let mut body: Vec<String> = Vec::new();
+                loop {
+                    let word = try!(tokens.word());
+                    if word == "}" {
+                        break;
+                    }
+                    body.push(word);
+                }
+                parsed_rules.push((name, body, line));
+            }
+            _ => {
+                let type_name = word;
+                let type_id = match map.type_map.iter().find(|(_, name)| *name == type_name) {
+                    Some(&(id, _)) => id,
+                    None => return Err(tokens.error(&format!("unknown type {}", type_name))),
+                };
+                let bucket = try!(parse_bucket(&mut tokens, type_id));
+                parsed_buckets.push(bucket);
+            }
+        }
+    }
+
+    try!(add_buckets(&mut map, parsed_buckets));
+    for (name, body, line) in parsed_rules {
+        try!(add_rule(&mut map, name, body).map_err(|e| format!("line {}: {}", line, e)));
+    }
+    map.name_map.sort();
+    Ok(map)
+}
+
+fn parse_bucket(tokens: &mut Tokens, type_id: i32) -> Result<ParsedBucket, String> {
+    let mut bucket = ParsedBucket {
+        type_id: type_id,
+        name: try!(tokens.word()),
+        id: None,
+        alg: BucketAlg::Straw,
+        items: Vec::new(),
+    };
+    try!(tokens.expect("{"));
+    loop {
+        let word = try!(tokens.word());
+        match &word[..] {
+            "}" => break,
+            "id" => bucket.id = Some(try!(tokens.number::<i32>())),
+            "alg" => {
+                let alg = try!(tokens.word());
+                bucket.alg = match buckets::alg_from_name(&alg) {
+                    Some(alg) => alg,
+                    None => return Err(tokens.error(&format!("unknown bucket alg {}", alg))),
+                }
+            }
+            "hash" => {
+                let hash = try!(tokens.word());
+                if hash != "0" && hash != "rjenkins1" {
+                    return Err(tokens.error(&format!("unsupported hash {}", hash)));
+                }
+            }
+            "item" => {
+                let name = try!(tokens.word());
+                let mut weight: u32 = 65536;
+                // weight and pos may come in either order; items are kept in the order given
+                while tokens.peek() == Some("weight") || tokens.peek() == Some("pos") {
+                    if try!(tokens.word()) == "weight" {
+                        weight = try!(parse_weight(tokens));
+                    } else {
+                        try!(tokens.number::<u32>());
+                    }
+                }
+                bucket.items.push((name, weight));
+            }
+            other => {
+                return Err(tokens.error(&format!("unexpected {} in bucket {}", other, bucket.name)))
+            }
+        }
+    }
+    Ok(bucket)
+}
+
+fn parse_weight(tokens: &mut Tokens) -> Result<u32, String> {
+    let weight = try!(tokens.number::<f64>());
+    if weight < 0.0 {
+        return Err(tokens.error("weights cannot be negative"));
+    }
+    Ok((weight * 65536.0).round() as u32)
+}
+
+// Gives every bucket an id, resolves item names and places the buckets in their slots
+fn add_buckets(map: &mut CrushMap, parsed_buckets: Vec<ParsedBucket>) -> Result<(), String> {
+    let mut next_id: i32 = parsed_buckets.iter()
+        .filter_map(|bucket| bucket.id)
+        .min()
+        .unwrap_or(0) - 1;
+    let mut ids: Vec<i32> = Vec::new();
+    for bucket in &parsed_buckets {
+        let id = match bucket.id {
+            Some(id) => id,
+            None => {
+                next_id -= 1;
+                next_id + 1
+            }
+        };
+        if id >= 0 {
+            return Err(format!("bucket {} has a non-negative id {}", bucket.name, id));
+        }
+        if ids.contains(&id) {
+            return Err(format!("bucket id {} is used more than once", id));
+        }
+        if map.name_map.iter().any(|(_, name)| *name == bucket.name) {
+            return Err(format!("{} is defined more than once", bucket.name));
+        }
+        ids.push(id);
+        map.name_map.push((id, bucket.name.clone()));
+    }
+
+    for (bucket, id) in parsed_buckets.into_iter().zip(ids) {
+        let bucket_type = try!(buckets::type_code(bucket.type_id)
+            .ok_or(format!("bucket {} has type {} which crushtool cannot encode",
+                           bucket.name,
+                           bucket.type_id)));
+        let mut items: Vec<(i32, Option<String>)> = Vec::new();
+        let mut weights: Vec<u32> = Vec::new();
+        for &(ref name, weight) in &bucket.items {
+            let item = match map.name_map.iter().find(|(_, known)| known == name) {
+                Some(&(item, _)) => item,
+                None => return Err(format!("bucket {} holds unknown item {}", bucket.name, name)),
+            };
+            items.push((item, Some(name.clone())));
+            weights.push(weight);
+        }
+        let mut new_bucket = buckets::new_bucket(id, bucket_type, bucket.alg.clone());
+        try!(buckets::set_items(&mut new_bucket,
+                                items,
+                                weights,
+                                map.straw_calc_version.unwrap_or(0))
+            .map_err(|e| format!("bucket {}: {}", bucket.name, e)));
+        buckets::place(&mut map.buckets, new_bucket);
+    }
+    map.max_buckets = map.buckets.len() as i32;
+    Ok(())
+}
+
+// Rules are numbered in the order they appear, like `crushtool -c` does
+fn add_rule(map: &mut CrushMap, name: String, body: Vec<String>) -> Result<(), String> {
+    let id = map.rules.len();
+    let mut mask = CrushRuleMask {
+        ruleset: id as u8,
+        rule_type: RuleType::Replicated,
+        min_size: 1,
+        max_size: 10,
+    };
+    let mut steps: Vec<CrushRuleStep> = Vec::new();
+
+    let mut words = body.into_iter();
+    while let Some(word) = words.next() {
+        let mut value = || words.next().ok_or(format!("rule {} ends after {}", name, word));
+        match &word[..] {
+            // Luminous calls the ruleset "id"
+            "ruleset" | "id" => mask.ruleset = try!(number(&try!(value()))),
+            "type" => {
+                mask.rule_type = match &try!(value())[..] {
+                    "replicated" | "1" => RuleType::Replicated,
+                    "erasure" | "3" => RuleType::Erasure,
+                    other => return Err(format!("unknown rule type {}", other)),
+                }
+            }
+            "min_size" => mask.min_size = try!(number(&try!(value()))),
+            "max_size" => mask.max_size = try!(number(&try!(value()))),
+            "step" => {
+                let op = try!(value());
+                steps.push(try!(parse_step(map, &op, &mut words)));
+            }
+            other => return Err(format!("unexpected {} in rule {}", other, name)),
+        }
+    }
+
+    map.rules.push(Some(Rule {
+        mask: mask,
+        steps: steps,
+    }));
+    if !name.is_empty() {
+        map.rule_name_map.push((id as i32, name));
+    }
+    map.max_rules = map.rules.len() as u32;
+    Ok(())
+}
+
+fn parse_step<I: Iterator<Item = String>>(map: &CrushMap,
+                                         op: &str,
+                                         words: &mut I)
+                                         -> Result<CrushRuleStep, String> {
+    let mut value = || words.next().ok_or(format!("step {} is incomplete", op));
+    let step = |op: OpCode, arg1: i32, arg2: i32| {
+        CrushRuleStep {
+            op: op,
+            arg1: (arg1, None),
+            arg2: (arg2, None),
+        }
+    };
+    match op {
+        "take" => {
+            let name = try!(value());
+            match map.name_map.iter().find(|(_, known)| *known == name) {
+                Some(&(item, _)) => Ok(step(OpCode::Take, item, 0)),
+                None => Err(format!("step take of unknown item {}", name)),
+            }
+        }
+        "choose" | "chooseleaf" => {
+            let mode = try!(value());
+            let count: i32 = try!(number(&try!(value())));
+            if try!(value()) != "type" {
+                return Err(format!("step {} {} {} is missing a type", op, mode, count));
+            }
+            let type_name = try!(value());
+            let type_id = match map.type_map.iter().find(|(_, name)| *name == type_name) {
+                Some(&(id, _)) => id,
+                None => return Err(format!("unknown type {}", type_name)),
+            };
+            let code = match (op, &mode[..]) {
+                ("choose", "firstn") => OpCode::ChooseFirstN,
+                ("choose", "indep") => OpCode::ChooseIndep,
+                ("chooseleaf", "firstn") => OpCode::ChooseLeafFirstN,
+                ("chooseleaf", "indep") => OpCode::ChooseLeafIndep,
+                _ => return Err(format!("unknown mode {} for step {}", mode, op)),
+            };
+            Ok(step(code, count, type_id))
+        }
+        "emit" => Ok(step(OpCode::Emit, 0, 0)),
+        "noop" => Ok(step(OpCode::Noop, 0, 0)),
+        _ => {
+            let code = match op {
+                "set_choose_tries" => OpCode::SetChooseTries,
+                "set_chooseleaf_tries" => OpCode::SetChooseLeafTries,
+                "set_choose_local_tries" => OpCode::SetChooseLocalTries,
+                "set_choose_local_fallback_tries" => OpCode::SetChooseLocalFallbackTries,
+                "set_chooseleaf_vary_r" => OpCode::SetChooseLeafVaryR,
+                _ => return Err(format!("unknown step {}", op)),
+            };
+            Ok(step(code, try!(number(&try!(value()))), 0))
+        }
+    }
+}
+
+fn number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse::<T>().map_err(|_| format!("expected a number, found {}", word))
+}
+
+// Splits the text into words, dropping comments and remembering which line each word is on so
+// errors can point at it
+struct Tokens {
+    words: Vec<(usize, String)>,
+    position: usize,
+}
+
+impl Tokens {
+    fn new(text: &str) -> Tokens {
+        let mut words: Vec<(usize, String)> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let spaced = line.replace("{", " { ").replace("}", " } ");
+            for word in spaced.split_whitespace() {
+                words.push((index + 1, word.to_string()));
+            }
+        }
+        Tokens {
+            words: words,
+            position: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let word = self.words.get(self.position).map(|(_, word)| word.clone());
+        self.position += 1;
+        word
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.words.get(self.position).map(|(_, word)| &word[..])
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(word) => Ok(word),
+            None => Err("unexpected end of crushmap".to_string()),
+        }
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, String> {
+        let word = try!(self.word());
+        number(&word).map_err(|e| self.error(&e))
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let word = try!(self.word());
+        if word != expected {
+            return Err(self.error(&format!("expected {}, found {}", expected, word)));
+        }
+        Ok(())
+    }
+
+    // The line of the word most recently read
+    fn line(&self) -> usize {
+        match self.words.get(self.position.saturating_sub(1)) {
+            Some(&(line, _)) => line,
+            None => self.words.last().map(|&(line, _)| line).unwrap_or(0),
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line(), message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crushtool;
+
+    // A small Jewel map as `crushtool -d` writes it
+    const JEWEL_MAP: &str = "# begin crush map
+tunable choose_local_tries 0
+tunable choose_local_fallback_tries 0
+tunable choose_total_tries 50
+tunable chooseleaf_descend_once 1
+tunable chooseleaf_vary_r 1
+tunable straw_calc_version 1
+tunable allowed_bucket_algs 54
+
+# devices
+device 0 osd.0
+device 1 device1
+device 2 osd.2
+
+# types
+type 0 osd
+type 1 host
+type 10 root
+
+# buckets
+host node1 {
+\tid -2\t\t# do not change unnecessarily
+\t# weight 1.000
+\talg straw2
+\thash 0\t# rjenkins1
+\titem osd.0 weight 1.000
+}
+host node2 {
+\tid -3\t\t# do not change unnecessarily
+\t# weight 0.500
+\talg straw
+\thash 0\t# rjenkins1
+\titem osd.2 weight 0.500
+}
+root default {
+\tid -1\t\t# do not change unnecessarily
+\t# weight 1.500
+\talg straw2
+\thash 0\t# rjenkins1
+\titem node1 weight 1.000
+\titem node2 weight 0.500
+}
+
+# rules
+rule replicated_ruleset {
+\truleset 0
+\ttype replicated
+\tmin_size 1
+\tmax_size 10
+\tstep take default
+\tstep chooseleaf firstn 0 type host
+\tstep emit
+}
+
+# end crush map
+";
+
+    #[test]
+    fn parse_and_render_round_trip() {
+        let map = parse(JEWEL_MAP).unwrap();
+        assert_eq!(map.max_devices, 3);
+        assert_eq!(map.straw_calc_version, Some(1));
+        assert_eq!(item_name(&map, 1), "device1");
+        assert_eq!(type_name(&map, 10), "root");
+        let root = buckets::find(&map.buckets, -1).unwrap();
+        assert_eq!(buckets::item_weights(root), vec![0x10000, 0x8000]);
+        assert_eq!(render(&map), JEWEL_MAP);
+    }
+
+    #[test]
+    fn survives_crushtool_encoding() {
+        let map = parse(JEWEL_MAP).unwrap();
+        let bytes = crushtool::encode_crushmap(map).unwrap();
+        let decoded = crushtool::decode_crushmap(&bytes).unwrap();
+        assert_eq!(render(&decoded), JEWEL_MAP);
+    }
+
+    #[test]
+    fn legacy_tunables_are_left_out() {
+        let map = parse("type 0 osd\ntype 1 host\nhost empty {\n\tid -1\n}\n").unwrap();
+        assert_eq!(map.choose_total_tries, Some(19));
+        assert!(!render(&map).contains("tunable"));
+    }
+
+    #[test]
+    fn missing_ids_and_edited_algorithms() {
+        // A bucket without an id gets the one below the lowest id in use
+        let edited = JEWEL_MAP.replace("\tid -2\t\t# do not change unnecessarily\n", "")
+            .replace("alg straw\n", "alg tree\n");
+        let map = parse(&edited).unwrap();
+        let node1 = map.name_map.iter().find(|(_, name)| name == "node1").unwrap().0;
+        assert_eq!(node1, -4);
+        let bucket = buckets::find(&map.buckets, -3).unwrap();
+        assert_eq!(buckets::header(bucket).unwrap().alg, crushtool::BucketAlg::Tree);
+        assert_eq!(buckets::item_weights(bucket), vec![0x8000]);
+    }
+
+    #[test]
+    fn unsupported_text_is_an_error() {
+        let classes = JEWEL_MAP.replace("device 0 osd.0\n", "device 0 osd.0 class hdd\n");
+        assert!(parse(&classes).unwrap_err().contains("device classes"));
+        let hash = JEWEL_MAP.replace("hash 0\t# rjenkins1", "hash 3");
+        assert!(parse(&hash).unwrap_err().contains("unsupported hash"));
+        let unknown = JEWEL_MAP.replace("item osd.2", "item osd.9");
+        assert!(parse(&unknown).is_err());
+        assert!(parse("host x {\n item nope\n}").unwrap_err().contains("unknown type"));
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use cluster::Cluster;
+use state;
+
+// What one discovery run found: the racks the hosts were placed in and the links between hosts
+// that could see each other. Every create-crushmap run keeps its topology as a snapshot, so runs
+// can be compared to catch cabling changes and servers that were moved. A snapshot looks like:
+//
+//   rack rack-a host1 host2
+//   rack rack-c host3
+//   link host1 host2
+//
+// Links are the neighbors the nodes reported, before any hints are applied. Each link is written
+// once with its hosts in order.
+#[derive(Debug, PartialEq)]
+pub struct Topology {
+    pub racks: BTreeMap<String, BTreeSet<String>>,
+    pub links: BTreeSet<(String, String)>,
+}
+
+// How a newer topology differs from an older one
+pub struct Drift {
+    pub added: Vec<(String, String)>,
+    pub removed: Vec<(String, String)>,
+    // Hosts found in a different rack, with the old and new rack
+    pub moved: Vec<(String, String, String)>,
+    pub new_links: Vec<(String, String)>,
+    pub lost_links: Vec<(String, String)>,
+}
+
+impl Topology {
+    pub fn new(racks: &BTreeMap<String, Vec<String>>,
+               neighbors: &HashMap<String, Vec<String>>)
+               -> Topology {
+        let mut links: BTreeSet<(String, String)> = BTreeSet::new();
+        for (host, seen) in neighbors {
+            for neighbor in seen {
+                if neighbor != host {
+                    links.insert(link(host, neighbor));
+                }
+            }
+        }
+        Topology {
+            racks: racks.iter()
+                .map(|(rack, hosts)| (rack.clone(), hosts.iter().cloned().collect()))
+                .collect(),
+            links: links,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (rack, hosts) in &self.racks {
+            let hosts: Vec<&str> = hosts.iter().map(|host| host.as_str()).collect();
+            text.push_str(&format!("rack {} {}\n", rack, hosts.join(" ")));
+        }
+        for (a, b) in &self.links {
+            text.push_str(&format!("link {} {}\n", a, b));
+        }
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<Topology, String> {
+        let mut topology = Topology {
+            racks: BTreeMap::new(),
+            links: BTreeSet::new(),
+        };
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match (fields.first().copied(), fields.len()) {
+                (Some("rack"), len) if len >= 2 => {
+                    let hosts = fields[2..].iter().map(|host| host.to_string()).collect();
+                    topology.racks.insert(fields[1].to_string(), hosts);
+                }
+                (Some("link"), 3) => {
+                    topology.links.insert(link(fields[1], fields[2]));
+                }
+                (None, _) => {}
+                _ => return Err(format!("Could not understand the topology line {}", line)),
+            }
+        }
+        Ok(topology)
+    }
+
+    // The rack each host is in
+    pub fn placements(&self) -> BTreeMap<&str, &str> {
+        let mut placements: BTreeMap<&str, &str> = BTreeMap::new();
+        for (rack, hosts) in &self.racks {
+            for host in hosts {
+                placements.insert(host, rack);
+            }
+        }
+        placements
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} hosts in {} racks, {} links",
+                self.placements().len(),
+                self.racks.len(),
+                self.links.len())
+    }
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty() &&
+        self.new_links.is_empty() && self.lost_links.is_empty()
+    }
+
+    // One change per line, like diff.rs
+    pub fn describe(&self) -> Vec<String> {
+        let mut changes: Vec<String> = Vec::new();
+        for (host, rack) in &self.added {
+            changes.push(format!("new host {} in {}", host, rack));
+        }
+        for (host, rack) in &self.removed {
+            changes.push(format!("host {} is gone from {}", host, rack));
+        }
+        for (host, from, to) in &self.moved {
+            changes.push(format!("host {} moved from {} to {}", host, from, to));
+        }
+        for (a, b) in &self.new_links {
+            changes.push(format!("new link between {} and {}", a, b));
+        }
+        for (a, b) in &self.lost_links {
+            changes.push(format!("lost link between {} and {}", a, b));
+        }
+        changes
+    }
+}
+
+// Compares two topologies. Hosts are matched by name. Racks are matched by the hosts they share
+// rather than by name, since a rack's name can change between runs, for instance when its first
+// host goes away or the rack-name-template changes, see naming.rs. Each new rack is paired with
+// the old rack it shares the most hosts with, and a host only counts as moved when its new rack
+// isn't paired with its old one.
+pub fn compare(old: &Topology, new: &Topology) -> Drift {
+    let old_placements = old.placements();
+    let new_placements = new.placements();
+    let pairs = pair_racks(old, new);
+    let mut drift = Drift {
+        added: Vec::new(),
+        removed: Vec::new(),
+        moved: Vec::new(),
+        new_links: new.links.difference(&old.links).cloned().collect(),
+        lost_links: old.links.difference(&new.links).cloned().collect(),
+    };
+    for (host, old_rack) in &old_placements {
+        match new_placements.get(host) {
+            None => drift.removed.push((host.to_string(), old_rack.to_string())),
+            Some(new_rack) if pairs.get(new_rack) != Some(old_rack) => {
+                drift.moved.push((host.to_string(), old_rack.to_string(), new_rack.to_string()))
+            }
+            Some(_) => {}
+        }
+    }
+    for (host, new_rack) in &new_placements {
+        if !old_placements.contains_key(host) {
+            drift.added.push((host.to_string(), new_rack.to_string()));
+        }
+    }
+    drift
+}
+
+// Pairs each new rack with an old rack, best overlap first. Racks that share no hosts with any
+// old rack are left unpaired.
+fn pair_racks<'a>(old: &'a Topology, new: &'a Topology) -> BTreeMap<&'a str, &'a str> {
+    let mut overlaps: Vec<(usize, &str, &str)> = Vec::new();
+    for (new_rack, new_hosts) in &new.racks {
+        for (old_rack, old_hosts) in &old.racks {
+            let shared = new_hosts.intersection(old_hosts).count();
+            if shared > 0 {
+                overlaps.push((shared, new_rack, old_rack));
+            }
+        }
+    }
+    // Largest overlap first, then by name so the pairing doesn't depend on map order
+    overlaps.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)).then(a.2.cmp(b.2)));
+
+    let mut pairs: BTreeMap<&str, &str> = BTreeMap::new();
+    let mut paired_old: BTreeSet<&str> = BTreeSet::new();
+    for (_, new_rack, old_rack) in overlaps {
+        if pairs.contains_key(new_rack) || paired_old.contains(old_rack) {
+            continue;
+        }
+        pairs.insert(new_rack, old_rack);
+        paired_old.insert(old_rack);
+    }
+    pairs
+}
+
+// Keeps the topology as the newest snapshot in the cluster's history
+pub fn save(cluster: &Cluster, topology: &Topology) -> Result<PathBuf, String> {
+    state::snapshot(cluster, "topology", topology.to_text().as_bytes())
+}
+
+// Every topology kept for the cluster with the time it was found, oldest first
+pub fn history(cluster: &Cluster) -> Result<Vec<(u64, Topology)>, String> {
+    let mut history: Vec<(u64, Topology)> = Vec::new();
+    for (time, path) in state::snapshots(cluster, "topology") {
+        let mut text = String::new();
+        try!(File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e)));
+        history.push((time, try!(Topology::from_text(&text))));
+    }
+    Ok(history)
+}
+
+// The topology the operator last accepted, by applying a crushmap built from it or with the
+// accept-topology action. Scheduled rounds of discovery are compared with it.
+pub fn accepted(cluster: &Cluster) -> Result<Option<Topology>, String> {
+    let mut text = String::new();
+    match File::open(cluster.path("accepted-topology")) {
+        Ok(mut file) => try!(file.read_to_string(&mut text).map_err(|e| e.to_string())),
+        Err(_) => return Ok(None),
+    };
+    Topology::from_text(&text).map(Some)
+}
+
+// Accepting a topology also clears any drift flagged against the one accepted before
+pub fn accept(cluster: &Cluster, topology: &Topology) -> Result<(), String> {
+    let mut file = try!(File::create(cluster.path("accepted-topology"))
+        .map_err(|e| e.to_string()));
+    try!(file.write_all(topology.to_text().as_bytes()).map_err(|e| e.to_string()));
+    match fs::remove_file(cluster.path("drift")) {
+        Err(ref e) if e.kind() != ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+// Keeps the drift a scheduled round found in the cluster's drift file, one change per line, until
+// a topology is accepted. The status stays blocked while it is there, see status.rs.
+pub fn flag_drift(cluster: &Cluster, changes: &[String]) -> Result<(), String> {
+    let mut file = try!(File::create(cluster.path("drift")).map_err(|e| e.to_string()));
+    file.write_all(changes.join("\n").as_bytes()).map_err(|e| e.to_string())
+}
+
+// The flagged changes, None when there is no unreviewed drift
+pub fn drift_flag(cluster: &Cluster) -> Option<Vec<String>> {
+    let mut text = String::new();
+    match File::open(cluster.path("drift")).and_then(|mut file| file.read_to_string(&mut text)) {
+        Ok(_) => Some(text.lines().map(|line| line.to_string()).collect()),
+        Err(_) => None,
+    }
+}
+
+fn link(a: &str, b: &str) -> (String, String) {
+    if a < b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn topology(racks: &[(&str, &[&str])], links: &[(&str, &str)]) -> Topology {
+        let racks: BTreeMap<String, Vec<String>> = racks.iter()
+            .map(|&(rack, hosts)| (rack.to_string(), hosts.iter().map(|h| h.to_string()).collect()))
+            .collect();
+        let mut neighbors: HashMap<String, Vec<String>> = HashMap::new();
+        for &(a, b) in links {
+            neighbors.entry(a.to_string()).or_default().push(b.to_string());
+        }
+        Topology::new(&racks, &neighbors)
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let found = topology(&[("rack-a", &["host1", "host2"]), ("rack-c", &["host3"])],
+                             &[("host2", "host1")]);
+        let text = found.to_text();
+        assert_eq!(text, "rack rack-a host1 host2\nrack rack-c host3\nlink host1 host2\n");
+        assert_eq!(Topology::from_text(&text).unwrap(), found);
+        assert!(Topology::from_text("shelf rack-a host1").is_err());
+    }
+
+    #[test]
+    fn same_topology_has_no_drift() {
+        let found = topology(&[("rack-a", &["host1", "host2"]), ("rack-c", &["host3"])],
+                             &[("host1", "host2")]);
+        assert!(compare(&found, &found).is_empty());
+    }
+
+    #[test]
+    fn renamed_rack_is_not_drift() {
+        let old = topology(&[("rack-a", &["host1", "host2"]), ("rack-c", &["host3", "host4"])],
+                           &[("host1", "host2"), ("host3", "host4")]);
+        let new = topology(&[("rack-x", &["host1", "host2"]), ("rack-a", &["host3", "host4"])],
+                           &[("host1", "host2"), ("host3", "host4")]);
+        let drift = compare(&old, &new);
+        assert!(drift.is_empty(), "{:?}", drift.describe());
+    }
+
+    #[test]
+    fn moved_host_is_drift() {
+        let old = topology(&[("rack-a", &["host1", "host2", "host3"]), ("rack-d", &["host4"])],
+                           &[]);
+        let new = topology(&[("rack-a", &["host1", "host2"]), ("rack-d", &["host3", "host4"])],
+                           &[]);
+        let drift = compare(&old, &new);
+        assert_eq!(drift.moved,
+                   vec![("host3".to_string(), "rack-a".to_string(), "rack-d".to_string())]);
+        assert!(drift.added.is_empty() && drift.removed.is_empty());
+    }
+
+    #[test]
+    fn hosts_and_links_that_come_and_go() {
+        let old = topology(&[("rack-a", &["host1", "host2"])], &[("host1", "host2")]);
+        let new = topology(&[("rack-a", &["host1", "host5"])], &[("host1", "host5")]);
+        let drift = compare(&old, &new);
+        assert_eq!(drift.added, vec![("host5".to_string(), "rack-a".to_string())]);
+        assert_eq!(drift.removed, vec![("host2".to_string(), "rack-a".to_string())]);
+        assert_eq!(drift.new_links, vec![("host1".to_string(), "host5".to_string())]);
+        assert_eq!(drift.lost_links, vec![("host1".to_string(), "host2".to_string())]);
+        assert!(drift.moved.is_empty());
+        assert_eq!(drift.describe().len(), 4);
+    }
+}
@@ -0,0 +1,292 @@
+use crushtool::CrushMap;
+
+use buckets;
+use naming;
+use rules;
+use topology::Topology;
+
+// Hand edits for the add-units and remove-units actions, so hosts can be placed or taken out
+// without running discovery again. The edit is made to the recorded topology, see topology.rs,
+// and the crushmap is regenerated from it.
+//
+// Paths name buckets from the root down and end in the host's rack, such as "default/rack-a".
+// A bucket's type can be given as "row=row-1". A rack that doesn't exist yet is created, but the
+// buckets above the racks come from the root and the levels in the rack hints, see hints.rs, so
+// they have to be there already.
+
+// Moves a host into the rack at the end of the path. Racks left empty are dropped.
+pub fn add_unit(topology: &mut Topology, host: &str, path: &str) -> Result<(), String> {
+    let rack = try!(rack_of(path));
+    for hosts in topology.racks.values_mut() {
+        hosts.remove(host);
+    }
+    topology.racks.entry(rack.clone()).or_default().insert(host.to_string());
+    topology.racks.retain(|_, hosts| !hosts.is_empty());
+    println!("Added {} to {}", host, rack);
+    Ok(())
+}
+
+// Takes a host out of its rack. The regenerated map keeps the host unlinked, like
+// `ceph osd crush unlink`, so no rule can reach it and Ceph moves its data elsewhere. When a
+// path is given the host has to be in the rack it ends with.
+pub fn remove_unit(topology: &mut Topology, host: &str, path: &str) -> Result<(), String> {
+    let rack = match topology.placements().get(host) {
+        Some(rack) => rack.to_string(),
+        None => return Err(format!("{} is not in any rack", host)),
+    };
+    if !path.is_empty() && try!(rack_of(path)) != rack {
+        return Err(format!("{} is not under {}", host, path));
+    }
+    if let Some(hosts) = topology.racks.get_mut(&rack) {
+        hosts.remove(host);
+    }
+    topology.racks.retain(|_, hosts| !hosts.is_empty());
+    println!("Removed {} from {}", host, rack);
+    Ok(())
+}
+
+// Checks the regenerated map has every bucket of the path, each under the one before it and of
+// the type given for it
+pub fn check_path(map: &CrushMap, path: &str) -> Result<(), String> {
+    let mut parent: Option<(i32, String)> = None;
+    for (type_name, name) in try!(parse_path(path)) {
+        let id = match find_name(map, &name) {
+            Some(id) if id < 0 => id,
+            _ => return Err(format!("{} is not a bucket in the crushmap", name)),
+        };
+        if let Some(type_name) = type_name {
+            let type_id = try!(rules::type_id(&type_name, &map.type_map));
+            let bucket_type = buckets::find(&map.buckets, id)
+                .and_then(buckets::header)
+                .map(|header| header.bucket_type.clone() as i32);
+            if bucket_type != Some(type_id) {
+                return Err(format!("{} is not a {}", name, type_name));
+            }
+        }
+        if let Some((parent_id, parent_name)) = parent {
+            if buckets::parent_of(&map.buckets, id) != Some(parent_id) {
+                return Err(format!("{} is not under {}. Buckets above the racks come from the \
+                                    levels in the rack hints",
+                                   name,
+                                   parent_name));
+            }
+        }
+        parent = Some((id, name));
+    }
+    Ok(())
+}
+
+// Keeps a host in the map without linking it anywhere. Hosts the regenerated map doesn't have
+// are copied over from the cluster's map along with their devices, so the devices are still
+// accounted for.
+pub fn unlink(map: &mut CrushMap, cluster_map: &CrushMap, host: &str) -> Result<(), String> {
+    let straw_calc_version = map.straw_calc_version.unwrap_or(0);
+    let host_id = match find_name(map, host) {
+        Some(id) if buckets::find(&map.buckets, id).is_some() => id,
+        Some(id) if id >= 0 => return Err(format!("{} is a device, not a host", host)),
+        _ => try!(copy_host(map, cluster_map, host)),
+    };
+    if let Some(parent) = buckets::parent_of(&map.buckets, host_id) {
+        if let Some(bucket) = buckets::find_mut(&mut map.buckets, parent) {
+            try!(buckets::remove_item(bucket, host_id, straw_calc_version));
+        }
+        println!("Unlinked {} from bucket {}", host, parent);
+    }
+    buckets::reweight(&mut map.buckets, straw_calc_version)
+}
+
+// The rack at the end of a path
+fn rack_of(path: &str) -> Result<String, String> {
+    match try!(parse_path(path)).pop() {
+        Some((None, rack)) => Ok(rack),
+        Some((Some(ref type_name), rack)) if type_name == "rack" => Ok(rack),
+        Some((Some(type_name), _)) => {
+            Err(format!("{} has to end in the rack the host goes in, not a {}", path, type_name))
+        }
+        None => Err("A path such as default/rack-a is needed".to_string()),
+    }
+}
+
+// Splits "default/row=row-1/rack-a" into (type, name) pairs
+fn parse_path(path: &str) -> Result<Vec<(Option<String>, String)>, String> {
+    let mut segments: Vec<(Option<String>, String)> = Vec::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let mut parts = segment.splitn(2, '=');
+        let first = parts.next().unwrap_or("").to_string();
+        let segment = match parts.next() {
+            Some(name) => (Some(first), name.to_string()),
+            None => (None, first),
+        };
+        if naming::sanitize(&segment.1) != segment.1 {
+            return Err(format!("{} is not a valid bucket name", segment.1));
+        }
+        segments.push(segment);
+    }
+    if segments.is_empty() {
+        return Err("A path such as default/rack-a is needed".to_string());
+    }
+    Ok(segments)
+}
+
+// Copies a host bucket and the names of its devices from the cluster's map
+fn copy_host(map: &mut CrushMap, cluster_map: &CrushMap, host: &str) -> Result<i32, String> {
+    let host_id = match find_name(cluster_map, host) {
+        Some(id) if id < 0 => id,
+        _ => return Err(format!("{} is not in the current crushmap", host)),
+    };
+    if buckets::find(&map.buckets, host_id).is_some() {
+        return Err(format!("Bucket id {} of {} is already taken in the generated map",
+                           host_id,
+                           host));
+    }
+    let bucket = match buckets::find(&cluster_map.buckets, host_id) {
+        Some(bucket) => bucket.clone(),
+        None => return Err(format!("Could not find the bucket for {}", host)),
+    };
+    for &(item, _) in &buckets::header(&bucket).unwrap().items {
+        if item < 0 {
+            return Err(format!("{} holds other buckets and cannot be copied", host));
+        }
+        if let Some((_, name)) = cluster_map.name_map.iter().find(|&&(id, _)| id == item) {
+            if find_name(map, name).is_none() {
+                map.name_map.push((item, name.clone()));
+            }
+        }
+    }
+    buckets::place(&mut map.buckets, bucket);
+    map.name_map.retain(|(_, name)| name != host);
+    map.name_map.push((host_id, host.to_string()));
+    map.name_map.sort();
+    map.max_buckets = map.buckets.len() as i32;
+    map.max_devices = buckets::device_bound(&map.buckets, &map.name_map);
+    Ok(host_id)
+}
+
+fn find_name(map: &CrushMap, name: &str) -> Option<i32> {
+    map.name_map.iter().find(|(_, known)| known == name).map(|&(id, _)| id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use text;
+
+    const MAP: &str = "device 0 osd.0
+device 1 osd.1
+device 2 osd.2
+type 0 osd
+type 1 host
+type 2 rack
+type 3 row
+type 10 root
+host node1 {
+\tid -2
+\talg straw2
+\titem osd.0 weight 1.000
+}
+host node2 {
+\tid -3
+\talg straw2
+\titem osd.1 weight 1.000
+}
+rack rack-a {
+\tid -4
+\talg straw2
+\titem node1 weight 1.000
+\titem node2 weight 1.000
+}
+row row-1 {
+\tid -5
+\talg straw2
+\titem rack-a weight 2.000
+}
+root default {
+\tid -1
+\talg straw2
+\titem row-1 weight 2.000
+}
+";
+
+    fn topology() -> Topology {
+        Topology::from_text("rack rack-a node1 node2\nrack rack-b node3\n").unwrap()
+    }
+
+    fn rack(topology: &Topology, host: &str) -> Option<String> {
+        topology.placements().get(host).map(|rack| rack.to_string())
+    }
+
+    #[test]
+    fn paths_can_give_bucket_types() {
+        assert_eq!(parse_path("/default/row=row-1/rack-a/").unwrap(),
+                   vec![(None, "default".to_string()),
+                        (Some("row".to_string()), "row-1".to_string()),
+                        (None, "rack-a".to_string())]);
+        assert!(parse_path("default/rack a").is_err());
+        assert!(parse_path("//").is_err());
+    }
+
+    #[test]
+    fn paths_have_to_end_in_a_rack() {
+        assert_eq!(rack_of("default/rack=rack-c").unwrap(), "rack-c");
+        assert!(add_unit(&mut topology(), "node1", "default/row=row-1").is_err());
+    }
+
+    #[test]
+    fn adding_moves_the_host_and_drops_empty_racks() {
+        let mut topology = topology();
+        add_unit(&mut topology, "node3", "default/row=row-1/rack-a").unwrap();
+        assert_eq!(rack(&topology, "node3"), Some("rack-a".to_string()));
+        assert!(!topology.racks.contains_key("rack-b"));
+
+        add_unit(&mut topology, "node4", "default/rack-new").unwrap();
+        assert_eq!(rack(&topology, "node4"), Some("rack-new".to_string()));
+    }
+
+    #[test]
+    fn removal_checks_the_path() {
+        let mut topology = topology();
+        assert_eq!(remove_unit(&mut topology, "node1", "default/rack-b"),
+                   Err("node1 is not under default/rack-b".to_string()));
+        assert_eq!(rack(&topology, "node1"), Some("rack-a".to_string()));
+        assert!(remove_unit(&mut topology, "node4", "").is_err());
+
+        remove_unit(&mut topology, "node3", "default/rack-b").unwrap();
+        assert_eq!(rack(&topology, "node3"), None);
+        assert!(!topology.racks.contains_key("rack-b"));
+    }
+
+    #[test]
+    fn paths_are_checked_against_the_map() {
+        let map = text::parse(MAP).unwrap();
+        check_path(&map, "default/row=row-1/rack-a").unwrap();
+        assert_eq!(check_path(&map, "default/rack-b"),
+                   Err("rack-b is not a bucket in the crushmap".to_string()));
+        assert_eq!(check_path(&map, "default/rack=row-1/rack-a"),
+                   Err("row-1 is not a rack".to_string()));
+        assert!(check_path(&map, "default/rack-a").is_err());
+        assert!(check_path(&map, "default/row=row-1/osd.0").is_err());
+    }
+
+    #[test]
+    fn unlinked_hosts_keep_their_devices() {
+        let cluster_map = text::parse(MAP).unwrap();
+        let mut map = cluster_map.clone();
+        unlink(&mut map, &cluster_map, "node1").unwrap();
+        assert_eq!(buckets::parent_of(&map.buckets, -2), None);
+        assert_eq!(buckets::parent_of(&map.buckets, 0), Some(-2));
+        let rack = buckets::find(&map.buckets, -4).unwrap();
+        assert_eq!(buckets::header(rack).unwrap().items.len(), 1);
+
+        // A map generated without the host gets it copied from the cluster's
+        let mut map = text::parse(&MAP.replace("\titem node2 weight 1.000\n", "")
+                .replace("host node2 {\n\tid -3\n\talg straw2\n\titem osd.1 weight 1.000\n}\n",
+                         ""))
+            .unwrap();
+        unlink(&mut map, &cluster_map, "node2").unwrap();
+        assert_eq!(find_name(&map, "node2"), Some(-3));
+        assert_eq!(find_name(&map, "osd.1"), Some(1));
+        assert_eq!(buckets::parent_of(&map.buckets, -3), None);
+        assert!(unlink(&mut map, &cluster_map, "osd.0").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+use crushtool::{CrushMap, OpCode};
+use std::collections::{HashMap, HashSet};
+
+use buckets;
+
+// Problems found in a crushmap. Errors make the map unusable or unsafe to hand to Ceph, warnings
+// are worth a look but Ceph would accept the map.
+pub struct Report {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+// Checks the structure of a crushmap before it is encoded: bucket ids and slots, the bounds
+// Ceph uses to size its arrays, item references, weights, the shape of the hierarchy and the
+// rules. Every problem is reported rather than stopping at the first one.
+pub fn validate(map: &CrushMap) -> Report {
+    let mut report = Report {
+        errors: Vec::new(),
+        warnings: Vec::new(),
+    };
+    check_names(map, &mut report);
+    check_buckets(map, &mut report);
+    check_hierarchy(map, &mut report);
+    check_rules(map, &mut report);
+    report
+}
+
+fn check_names(map: &CrushMap, report: &mut Report) {
+    let mut seen: HashMap<&str, i32> = HashMap::new();
+    for &(id, ref name) in &map.name_map {
+        if let Some(other) = seen.insert(&name[..], id) {
+            report.errors.push(format!("Name {} is used by both {} and {}", name, other, id));
+        }
+        // The binary map doesn't mind, but the text form can't be compiled again
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            report.warnings.push(format!("Item {} has the name \"{}\", which crushtool cannot \
+                                          compile from text",
+                                         id,
+                                         name));
+        }
+    }
+}
+
+fn check_buckets(map: &CrushMap, report: &mut Report) {
+    let mut ids: HashSet<i32> = HashSet::new();
+    let type_ids: HashSet<i32> = map.type_map.iter().map(|&(id, _)| id).collect();
+
+    if map.max_buckets != map.buckets.len() as i32 {
+        report.errors.push(format!("max_buckets is {} but the map has {} bucket slots",
+                                   map.max_buckets,
+                                   map.buckets.len()));
+    }
+
+    for (slot, bucket) in map.buckets.iter().enumerate() {
+        let header = match buckets::header(bucket) {
+            Some(header) => header,
+            None => continue,
+        };
+        let id = header.id;
+        if !ids.insert(id) {
+            report.errors.push(format!("Bucket id {} is used more than once", id));
+        }
+        if id >= 0 {
+            report.errors.push(format!("Bucket id {} is not negative", id));
+        } else if (-1 - id) as usize != slot {
+            report.errors.push(format!("Bucket {} is in slot {} but Ceph will look for it in \
+                                        slot {}",
+                                       id,
+                                       slot,
+                                       -1 - id));
+        }
+        if !type_ids.contains(&(header.bucket_type.clone() as i32)) {
+            report.errors.push(format!("Bucket {} has type {} which is not in the type list",
+                                       id,
+                                       header.bucket_type.clone() as i32));
+        }
+        if !map.name_map.iter().any(|&(index, _)| index == id) {
+            report.warnings.push(format!("Bucket {} has no name", id));
+        }
+        if let Some(allowed) = map.allowed_bucket_algorithms {
+            if allowed != 0 && (allowed & (1 << (header.alg.clone() as u32))) == 0 {
+                report.warnings.push(format!("Bucket {} uses {:?}, which the \
+                                              allowed_bucket_algs tunable does not allow",
+                                             id,
+                                             header.alg));
+            }
+        }
+
+        let weights = buckets::item_weights(bucket);
+        if header.size as usize != header.items.len() || weights.len() != header.items.len() {
+            report.errors.push(format!("Bucket {} has {} items, size {} and {} item weights",
+                                       id,
+                                       header.items.len(),
+                                       header.size,
+                                       weights.len()));
+        }
+        let total: u64 = weights.iter().fold(0, |acc, &w| acc + w as u64);
+        if total != header.weight as u64 {
+            report.errors.push(format!("Bucket {} weighs {} but its items add up to {}",
+                                       id,
+                                       header.weight,
+                                       total));
+        }
+        if header.items.is_empty() {
+            report.warnings.push(format!("Bucket {} is empty", id));
+        }
+
+        for (&(item, _), &weight) in header.items.iter().zip(weights.iter()) {
+            if item >= 0 {
+                if item >= map.max_devices {
+                    report.errors.push(format!("Bucket {} holds osd.{} but max_devices is {}",
+                                               id,
+                                               item,
+                                               map.max_devices));
+                }
+                continue;
+            }
+            match buckets::find(&map.buckets, item) {
+                None => {
+                    report.errors.push(format!("Bucket {} holds bucket {} which does not exist",
+                                               id,
+                                               item))
+                }
+                Some(child) => {
+                    if buckets::weight(child) != weight {
+                        report.warnings.push(format!("Bucket {} gives bucket {} weight {} but \
+                                                      it weighs {}",
+                                                     id,
+                                                     item,
+                                                     weight,
+                                                     buckets::weight(child)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Each bucket should hang from at most one parent and the hierarchy must not loop back on itself
+fn check_hierarchy(map: &CrushMap, report: &mut Report) {
+    let mut parents: HashMap<i32, Vec<i32>> = HashMap::new();
+    for bucket in &map.buckets {
+        if let Some(header) = buckets::header(bucket) {
+            for &(item, _) in &header.items {
+                parents.entry(item).or_default().push(header.id);
+            }
+        }
+    }
+    let mut items: Vec<&i32> = parents.keys().collect();
+    items.sort();
+    for item in items {
+        let holders = &parents[item];
+        if holders.len() > 1 {
+            let kind = if *item >= 0 { "Device" } else { "Bucket" };
+            report.warnings.push(format!("{} {} is held by more than one bucket: {:?}",
+                                         kind,
+                                         item,
+                                         holders));
+        }
+    }
+
+    for bucket in &map.buckets {
+        if let Some(id) = buckets::id(bucket) {
+            let mut current = id;
+            let mut depth = 0;
+            while let Some(parent) = parents.get(&current).and_then(|holders| holders.first()) {
+                current = *parent;
+                depth += 1;
+                if current == id || depth > map.buckets.len() {
+                    report.errors.push(format!("Bucket {} is part of a loop", id));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn check_rules(map: &CrushMap, report: &mut Report) {
+    if map.max_rules != map.rules.len() as u32 {
+        report.errors.push(format!("max_rules is {} but the map has {} rule slots",
+                                   map.max_rules,
+                                   map.rules.len()));
+    }
+    if map.rules.iter().all(|rule| rule.is_none()) {
+        report.errors.push("The map has no rules".to_string());
+    }
+    let type_ids: HashSet<i32> = map.type_map.iter().map(|&(id, _)| id).collect();
+    let mut rulesets: HashSet<u8> = HashSet::new();
+
+    for (index, rule) in map.rules.iter().enumerate() {
+        let rule = match *rule {
+            Some(ref rule) => rule,
+            None => continue,
+        };
+        if !rulesets.insert(rule.mask.ruleset) {
+            report.warnings.push(format!("Ruleset {} is shared by more than one rule",
+                                         rule.mask.ruleset));
+        }
+        if rule.mask.min_size > rule.mask.max_size {
+            report.errors.push(format!("Rule {} has min_size {} above max_size {}",
+                                       index,
+                                       rule.mask.min_size,
+                                       rule.mask.max_size));
+        }
+        if !rule.steps.iter().any(|step| step.op == OpCode::Emit) {
+            report.errors.push(format!("Rule {} never emits", index));
+        }
+        for step in &rule.steps {
+            match step.op {
+                OpCode::Take => {
+                    let item = step.arg1.0;
+                    let exists = if item >= 0 {
+                        item < map.max_devices
+                    } else {
+                        buckets::find(&map.buckets, item).is_some()
+                    };
+                    if !exists {
+                        report.errors.push(format!("Rule {} takes item {} which does not exist",
+                                                   index,
+                                                   item));
+                    }
+                }
+                OpCode::ChooseFirstN |
+                OpCode::ChooseIndep |
+                OpCode::ChooseLeafFirstN |
+                OpCode::ChooseLeafIndep if !type_ids.contains(&step.arg2.0) => {
+                    report.errors.push(format!("Rule {} chooses type {} which is not in the \
+                                                type list",
+                                               index,
+                                               step.arg2.0));
+                }
+                _ => {}
+            }
+        }
+    }
+    for &(id, ref name) in &map.rule_name_map {
+        if id < 0 || id as usize >= map.rules.len() || map.rules[id as usize].is_none() {
+            report.errors.push(format!("Rule name {} refers to rule {} which does not exist",
+                                       name,
+                                       id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buckets;
+    use crushtool::CrushMap;
+    use text;
+
+    const MAP: &str = "tunable straw_calc_version 1
+tunable allowed_bucket_algs 54
+device 0 osd.0
+device 1 osd.1
+type 0 osd
+type 1 host
+type 10 root
+host node1 {
+\tid -2
+\talg straw2
+\titem osd.0 weight 1.000
+\titem osd.1 weight 1.000
+}
+root default {
+\tid -1
+\talg straw2
+\titem node1 weight 2.000
+}
+rule replicated_ruleset {
+\truleset 0
+\ttype replicated
+\tmin_size 1
+\tmax_size 10
+\tstep take default
+\tstep chooseleaf firstn 0 type host
+\tstep emit
+}
+";
+
+    fn map() -> CrushMap {
+        text::parse(MAP).unwrap()
+    }
+
+    fn has(messages: &[String], text: &str) -> bool {
+        messages.iter().any(|message| message.contains(text))
+    }
+
+    #[test]
+    fn good_map_is_clean() {
+        let report = validate(&map());
+        assert!(report.is_valid(), "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+
+    #[test]
+    fn straw2_needs_allowing() {
+        // The legacy allowed_bucket_algs leaves straw2 out
+        let map = text::parse(&MAP.replace("tunable allowed_bucket_algs 54\n", "")).unwrap();
+        let report = validate(&map);
+        assert!(report.is_valid(), "{:?}", report.errors);
+        assert!(has(&report.warnings,
+                    "Bucket -1 uses Straw2, which the allowed_bucket_algs tunable does not allow"));
+    }
+
+    #[test]
+    fn weights_must_add_up() {
+        let mut map = map();
+        buckets::header_mut(buckets::find_mut(&mut map.buckets, -1).unwrap()).unwrap().weight = 1;
+        let report = validate(&map);
+        assert!(has(&report.errors, "Bucket -1 weighs 1 but its items add up to 131072"));
+
+        let mut map = self::map();
+        let node1 = buckets::find_mut(&mut map.buckets, -2).unwrap();
+        buckets::set_items(node1, vec![(0, None)], vec![0x10000], 1).unwrap();
+        let report = validate(&map);
+        assert!(report.is_valid(), "{:?}", report.errors);
+        assert!(has(&report.warnings, "Bucket -1 gives bucket -2 weight 131072 but it weighs"));
+    }
+
+    #[test]
+    fn buckets_must_be_in_their_slots() {
+        let mut map = map();
+        map.buckets.swap(0, 1);
+        let report = validate(&map);
+        assert!(has(&report.errors, "Bucket -2 is in slot 0"));
+        assert!(has(&report.errors, "Bucket -1 is in slot 1"));
+    }
+
+    #[test]
+    fn references_must_exist() {
+        let mut map = map();
+        map.max_devices = 1;
+        buckets::add_item(buckets::find_mut(&mut map.buckets, -1).unwrap(), -7, None, 0, 1)
+            .unwrap();
+        let report = validate(&map);
+        assert!(has(&report.errors, "Bucket -2 holds osd.1 but max_devices is 1"));
+        assert!(has(&report.errors, "Bucket -1 holds bucket -7 which does not exist"));
+    }
+
+    #[test]
+    fn loops_are_errors() {
+        let mut map = map();
+        buckets::add_item(buckets::find_mut(&mut map.buckets, -2).unwrap(), -1, None, 0, 1)
+            .unwrap();
+        let report = validate(&map);
+        assert!(has(&report.errors, "Bucket -1 is part of a loop"));
+    }
+
+    #[test]
+    fn rules_are_checked() {
+        let mut map = map();
+        {
+            let rule = map.rules[0].as_mut().unwrap();
+            rule.steps.retain(|step| step.op != OpCode::Emit);
+            rule.steps[0].arg1.0 = -9;
+            rule.mask.min_size = 11;
+        }
+        let report = validate(&map);
+        assert!(has(&report.errors, "Rule 0 never emits"));
+        assert!(has(&report.errors, "Rule 0 takes item -9 which does not exist"));
+        assert!(has(&report.errors, "Rule 0 has min_size 11 above max_size 10"));
+
+        map.rules.clear();
+        map.max_rules = 0;
+        let report = validate(&map);
+        assert!(has(&report.errors, "The map has no rules"));
+        assert!(has(&report.errors, "Rule name replicated_ruleset refers to rule 0"));
+    }
+
+    #[test]
+    fn names_are_checked() {
+        let mut map = map();
+        map.name_map.retain(|&(id, _)| id != -2);
+        map.name_map.push((1, "osd.0".to_string()));
+        let report = validate(&map);
+        assert!(has(&report.errors, "Name osd.0 is used by both"));
+        assert!(has(&report.warnings, "Bucket -2 has no name"));
+    }
+}
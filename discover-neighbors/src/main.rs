@@ -11,26 +11,73 @@ use std::time::Duration;
 use std::thread::sleep;
 
 mod networking;
+mod record;
 
 
 fn main() {
+    // The node actions run this binary too, telling themselves apart by the action name
+    match juju::action_name() {
+        Ok(ref action) if action == "show-data" => show_data(),
+        Ok(ref action) if action == "discover-neighbors" => discover_neighbors(),
+        _ => discovery_hook(),
+    }
+}
+
+fn discovery_hook() {
     let unit_id = env::var("JUJU_UNIT_NAME").unwrap_or("".to_string());
     let unit = parse_unit_into_relation(unit_id);
 
-    let ready_status: String = juju::relation_get("ready").unwrap().to_string();
+    // The controller may not have set ready yet, or the relation may be going away, so a key
+    // that can't be read means there is nothing to do this time
+    let ready_status: String = match juju::relation_get("ready") {
+        Ok(ready) => ready,
+        Err(e) => {
+            println!("Could not read the ready flag, not starting discovery: {}",
+                     e.to_string());
+            return;
+        }
+    };
     let ready_status = ready_status.trim_matches('\n').trim();
-    let finished_status: String =
-        juju::relation_get_by_unit("finished", &unit).unwrap().to_string();
+    let finished_status: String = juju::relation_get_by_unit("finished", &unit)
+        .unwrap_or_default();
     let finished_status = finished_status.trim_matches('\n').trim();
     let results: String;
 
+    // The controller numbers each round of discovery. "finished" holds the last epoch this node
+    // completed, so a new round starts discovery again. Controllers that predate epochs only set
+    // ready, which counts as epoch 1, the same as the old finished flag.
+    let epoch: u64 = juju::relation_get("epoch")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or(1);
+    let finished_epoch: u64 = finished_status.parse::<u64>().unwrap_or(0);
 
 
-
-    println!("Ready status: {}", ready_status);
-    if (ready_status == "1") && (finished_status != "1") {
+    println!("Ready status: {}, epoch {}, last finished {}",
+             ready_status,
+             epoch,
+             finished_epoch);
+    if (ready_status == "1") && (finished_epoch < epoch) {
         println!("Starting network discovery");
-        results = network_discovery(unit.name.clone()).trim_matches('\n').trim().to_string();
+        set_discovery_state(epoch, "running", "");
+        let mut record = match network_discovery(unit.name.clone()) {
+            Ok(record) => record,
+            Err(e) => {
+                println!("Network discovery failed: {}", e);
+                set_discovery_state(epoch, "failed", &e);
+                // Replace the last run's record, so show-data doesn't report an older epoch
+                let mut record = record::DiscoveryRecord::new();
+                record.epoch = epoch;
+                record.finished = record::unix_time();
+                record.error = Some(e);
+                if let Err(e) = record.save() {
+                    println!("Could not save the discovery record: {}", e);
+                }
+                return;
+            }
+        };
+        record.epoch = epoch;
+        results = record.neighbors.join(" ");
         println!("Results: {}", results);
 
         juju::relation_set("neighbors", &results);
@@ -43,9 +90,10 @@ fn main() {
             if test_set == results {
                 juju::status_set(juju::Status {
                     status_type: juju::StatusType::Waiting,
-                    message: "Finished network discovery".to_string(),
+                    message: format!("Finished network discovery (epoch {})", epoch),
                 });
-                juju::relation_set("finished", "1");
+                let _ = juju::relation_set("finished", &epoch.to_string());
+                set_discovery_state(epoch, "finished", "");
                 finished = true;
 
             } else {
@@ -54,15 +102,35 @@ fn main() {
                 sleep(Duration::new(5, 0));
             }
         }
+        if !finished {
+            let error = "The neighbors set on the relation never read back the same";
+            set_discovery_state(epoch, "failed", error);
+            record.error = Some(error.to_string());
+        }
+
+        record.published = Some(results.clone());
+        record.confirmed = finished;
+        if let Err(e) = record.save() {
+            println!("Could not save the discovery record: {}", e);
+        }
     }
 
 
 
 }
 
-fn network_discovery(unit: String) -> String {
+// Tells the controller how this node's part of a discovery round is going. The state is one of
+// running, finished or failed, and the error says why it failed.
+fn set_discovery_state(epoch: u64, state: &str, error: &str) {
+    let _ = juju::relation_set("discovery-epoch", &epoch.to_string());
+    let _ = juju::relation_set("discovery-state", state);
+    let _ = juju::relation_set("discovery-error", error);
+}
+
+fn network_discovery(unit: String) -> Result<record::DiscoveryRecord, String> {
 
-    let juju_unit_list: String = juju::relation_get("related-units").unwrap();
+    let juju_unit_list: String = try!(juju::relation_get("related-units")
+        .map_err(|e| format!("Could not read related-units: {}", e.to_string())));
     println!("Unit list: {}", juju_unit_list);
 
     let mut juju_machine_ids_with_ip: HashMap<String, Ipv4Addr> = HashMap::new();
@@ -72,33 +140,195 @@ fn network_discovery(unit: String) -> String {
         println!("Unit to decompose: {}", unit);
 
         let relation = parse_unit_into_relation(unit.to_string());
-        let ip = juju::relation_get_by_unit("private-address", &relation).unwrap();
-        let hostname = juju::relation_get_by_unit("hostname", &relation).unwrap();
+        let ip = try!(juju::relation_get_by_unit("private-address", &relation)
+            .map_err(|e| format!("Could not read the address of {}: {}", unit, e.to_string())));
+        let hostname = try!(juju::relation_get_by_unit("hostname", &relation)
+            .map_err(|e| format!("Could not read the hostname of {}: {}", unit, e.to_string())));
         let ip = ip.trim();
-        juju_machine_ids_with_ip.insert(hostname, Ipv4Addr::from_str(&ip).unwrap());
+        let ip = try!(Ipv4Addr::from_str(ip)
+            .map_err(|_| format!("{} has the address {}, which is not IPv4", unit, ip)));
+        juju_machine_ids_with_ip.insert(hostname, ip);
     }
     juju_machine_ids_with_ip.remove(&unit);
     println!("Known IPs: {:?}", juju_machine_ids_with_ip);
 
+    Ok(probe_targets(juju_machine_ids_with_ip))
+
+}
+
+// ARPs for the given machines and records who answered
+fn probe_targets(juju_machine_ids_with_ip: HashMap<String, Ipv4Addr>) -> record::DiscoveryRecord {
+
+    let mut record = record::DiscoveryRecord::new();
+    record.targets = juju_machine_ids_with_ip.iter()
+        .map(|(machine, ip)| (machine.trim().to_string(), *ip))
+        .collect();
+    record.targets.sort();
+
     // Get list of neighbor IPs using arping
-    let neighbor_list = networking::send_and_receive(juju_machine_ids_with_ip);
-    let mut neighbors_formatted: String = "".to_string();
-
-    for (machine, ip) in neighbor_list {
-        let trimmed_machine = &machine.trim_matches('\n').trim();
-        neighbors_formatted = format!("{} {}",
-                                      &neighbors_formatted.trim_matches('\n')
-                                          .trim()
-                                          .to_string(),
-                                      &trimmed_machine);
+    let probe = networking::send_and_receive(juju_machine_ids_with_ip);
+
+    for (machine, _) in probe.neighbors {
+        record.neighbors.push(machine.trim_matches('\n').trim().to_string());
     }
+    record.neighbors.sort();
+    record.interfaces = probe.interfaces;
+    record.responders = probe.responders;
+    record.finished = record::unix_time();
 
+    record
 
-    let unit_id = env::var("JUJU_UNIT_NAME").unwrap_or("".to_string());
-    let unit = parse_unit_into_relation(unit_id);
+}
+
+// Runs discovery from this node alone, for troubleshooting a single host. The targets
+// parameter overrides who is probed, as "hostname=ip" pairs or bare IPs separated by spaces or
+// commas; otherwise the machines on the controller relation are probed. Nothing is set on the
+// relation and the record of the last hook run is left alone, so show-data still reports what
+// the controller was given.
+fn discover_neighbors() {
+    let targets = match juju::action_get("targets") {
+        Ok(ref targets) if !targets.trim().is_empty() => parse_targets(targets),
+        _ => relation_targets(),
+    };
+    let targets = match targets {
+        Ok(targets) => targets,
+        Err(e) => {
+            let _ = juju::action_fail(&e);
+            return;
+        }
+    };
+    if targets.is_empty() {
+        let _ = juju::action_fail("There are no machines to probe");
+        return;
+    }
+
+    println!("Probing {:?}", targets);
+    let record = probe_targets(targets);
+    report_record(&record);
+}
+
+// Empty entries are skipped, but a machine named twice is an error rather than one of its
+// addresses being dropped
+fn parse_targets(targets: &str) -> Result<HashMap<String, Ipv4Addr>, String> {
+    let mut parsed: HashMap<String, Ipv4Addr> = HashMap::new();
+    for target in targets.split(|c: char| c == ',' || c.is_whitespace()) {
+        if target.is_empty() {
+            continue;
+        }
+        let (machine, ip) = match target.find('=') {
+            Some(index) => (&target[..index], &target[index + 1..]),
+            None => (target, target),
+        };
+        if machine.is_empty() {
+            return Err(format!("Target {} has no hostname", target));
+        }
+        let ip = try!(Ipv4Addr::from_str(ip)
+            .map_err(|_| format!("{} is not an IPv4 address in target {}", ip, target)));
+        if parsed.insert(machine.to_string(), ip).is_some() {
+            return Err(format!("{} is given more than once in the targets", machine));
+        }
+    }
+    Ok(parsed)
+}
+
+// The machines the controllers listed on their relations, looked up by relation id since
+// actions don't run in a relation context. A node related to more than one controller probes
+// the machines of all of them.
+fn relation_targets() -> Result<HashMap<String, Ipv4Addr>, String> {
+    let relation_ids = try!(juju::relation_ids_by_identifier("controller")
+        .map_err(|e| e.to_string()));
+    if relation_ids.is_empty() {
+        return Err("There is no controller relation, give targets instead".to_string());
+    }
 
-    neighbors_formatted
+    let own_unit = env::var("JUJU_UNIT_NAME").unwrap_or("".to_string());
+    let mut targets: HashMap<String, Ipv4Addr> = HashMap::new();
+    let mut controllers_found = false;
+    for relation_id in &relation_ids {
+        let controllers = try!(juju::relation_list_by_id(relation_id)
+            .map_err(|e| e.to_string()));
+        let controller = match controllers.first() {
+            Some(controller) => controller,
+            None => continue,
+        };
+        controllers_found = true;
 
+        let unit_list = try!(juju::relation_get_by_id("related-units", relation_id, controller)
+            .map_err(|e| e.to_string()));
+        for unit in unit_list.split_whitespace().filter(|unit| *unit != own_unit) {
+            let relation = parse_unit_into_relation(unit.to_string());
+            let ip = try!(juju::relation_get_by_id("private-address", relation_id, &relation)
+                .map_err(|e| e.to_string()));
+            let hostname = try!(juju::relation_get_by_id("hostname", relation_id, &relation)
+                .map_err(|e| e.to_string()));
+            let ip = try!(Ipv4Addr::from_str(ip.trim())
+                .map_err(|_| format!("{} has no usable private-address", unit)));
+            let hostname = hostname.trim().to_string();
+            match targets.insert(hostname.clone(), ip) {
+                Some(known) if known != ip => {
+                    return Err(format!("{} is listed at both {} and {} by the controllers",
+                                       hostname,
+                                       known,
+                                       ip))
+                }
+                _ => {}
+            }
+        }
+    }
+    if !controllers_found {
+        return Err("The controller relation has no units".to_string());
+    }
+    Ok(targets)
+}
+
+// Returns what the last discovery run saw, for debugging one node's view of the network
+fn show_data() {
+    let record = match record::DiscoveryRecord::load() {
+        Ok(record) => record,
+        Err(e) => {
+            let _ = juju::action_fail(&e);
+            return;
+        }
+    };
+
+    report_record(&record);
+    match record.published {
+        Some(ref published) => {
+            let _ = juju::action_set("published.neighbors", published);
+            let _ = juju::action_set("published.confirmed", &record.confirmed.to_string());
+        }
+        None => {
+            let _ = juju::action_set("published.neighbors", "nothing was published");
+        }
+    }
+}
+
+fn report_record(record: &record::DiscoveryRecord) {
+    let targets: Vec<String> = record.targets
+        .iter()
+        .map(|&(ref machine, ip)| format!("{} {}", machine, ip))
+        .collect();
+    let responders: Vec<String> = record.responders
+        .iter()
+        .map(|responder| {
+            format!("{} {} on {} after {:.3}ms",
+                    responder.ip,
+                    responder.mac,
+                    responder.interface,
+                    record::milliseconds(responder.latency))
+        })
+        .collect();
+
+    let _ = juju::action_set("epoch", &record.epoch.to_string());
+    let _ = juju::action_set("started", &record.started.to_string());
+    let _ = juju::action_set("finished", &record.finished.to_string());
+    let _ = juju::action_set("interfaces", &record.interfaces.join(" "));
+    let _ = juju::action_set("targets", &targets.join("\n"));
+    let _ = juju::action_set("responders", &responders.join("\n"));
+    let _ = juju::action_set("neighbors", &record.neighbors.join(" "));
+    if let Some(ref error) = record.error {
+        let _ = juju::action_set("error", error);
+    }
 }
 
 fn parse_unit_into_relation(unit: String) -> juju::Relation {
@@ -110,3 +340,33 @@ fn parse_unit_into_relation(unit: String) -> juju::Relation {
     };
     parsed_unit
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_can_be_named_or_bare() {
+        let targets = parse_targets("host2=10.0.0.2, 10.0.0.3,,\n host4=10.0.0.4 ").unwrap();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets["host2"], Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(targets["10.0.0.3"], Ipv4Addr::new(10, 0, 0, 3));
+        assert_eq!(targets["host4"], Ipv4Addr::new(10, 0, 0, 4));
+        assert!(parse_targets(" , ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_targets_are_errors() {
+        assert_eq!(parse_targets("host2=10.0.0.300"),
+                   Err("10.0.0.300 is not an IPv4 address in target host2=10.0.0.300"
+                       .to_string()));
+        assert!(parse_targets("host2=").is_err());
+        assert!(parse_targets("host2").is_err());
+        assert!(parse_targets("host2=fe80::1").is_err());
+        assert_eq!(parse_targets("=10.0.0.2"),
+                   Err("Target =10.0.0.2 has no hostname".to_string()));
+        assert_eq!(parse_targets("host2=10.0.0.2 host2=10.0.0.3"),
+                   Err("host2 is given more than once in the targets".to_string()));
+        assert!(parse_targets("10.0.0.2,10.0.0.2").is_err());
+    }
+}
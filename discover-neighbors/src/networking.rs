@@ -13,20 +13,41 @@ use std::time::{Instant, Duration};
 use std::collections::HashMap;
 
 
-pub fn send_and_receive(juju_machine_list: HashMap<String, Ipv4Addr>) -> HashMap<String, Ipv4Addr> {
+// An ARP reply heard during discovery
+#[derive(Clone, Debug)]
+pub struct Responder {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub interface: String,
+    // Time from when the requests went out to when the reply arrived
+    pub latency: Duration,
+}
+
+// Everything one round of ARP discovery saw
+pub struct Probe {
+    pub interfaces: Vec<String>,
+    pub responders: Vec<Responder>,
+    // The juju machines that answered, by hostname
+    pub neighbors: HashMap<String, Ipv4Addr>,
+}
 
-    let mut nodes: Vec<Ipv4Addr> = vec![];
+pub fn send_and_receive(juju_machine_list: HashMap<String, Ipv4Addr>) -> Probe {
+
+    let mut responders: Vec<Responder> = vec![];
+    let mut interfaces: Vec<String> = vec![];
     let (transmit_channel, receiver_channel) = channel();
     let iface = get_network_interfaces();
+    let started = Instant::now();
 
     for interface in iface {
+        interfaces.push(interface.name.clone());
         let transmit_channel = transmit_channel.clone();
         let interface = interface.clone();
         let interface2 = interface.clone();
         let unitips = juju_machine_list.clone();
 
         thread::spawn(move || {
-            recieve_packets(interface, transmit_channel);
+            recieve_packets(interface, transmit_channel, started);
         });
         thread::spawn(move || {
             send_packets(interface2, unitips);
@@ -38,7 +59,7 @@ pub fn send_and_receive(juju_machine_list: HashMap<String, Ipv4Addr>) -> HashMap
     while now.elapsed() <= Duration::new(10, 0) {
         match receiver_channel.try_recv() {
             Ok(item) => {
-                nodes.push(item);
+                responders.push(item);
                 now = Instant::now()
             }
             Err(e) => {
@@ -49,11 +70,15 @@ pub fn send_and_receive(juju_machine_list: HashMap<String, Ipv4Addr>) -> HashMap
     let mut neighbors: HashMap<String, Ipv4Addr> = HashMap::new();
 
     for (machine, ip) in juju_machine_list {
-        if nodes.contains(&ip) {
+        if responders.iter().any(|responder| responder.ip == ip) {
             neighbors.insert(machine, ip);
         }
     }
-    neighbors
+    Probe {
+        interfaces: interfaces,
+        responders: responders,
+        neighbors: neighbors,
+    }
 }
 
 
@@ -130,8 +155,8 @@ pub fn send_packets(interface: NetworkInterface, juju_machines: HashMap<String,
     }
 }
 
-// Receive packets given an interface
-pub fn recieve_packets(interface: NetworkInterface, tx: Sender<Ipv4Addr>) {
+// Receive packets given an interface, timing replies from when discovery started
+pub fn recieve_packets(interface: NetworkInterface, tx: Sender<Responder>, started: Instant) {
     // Create the receiver channel to receive packets
     let (_, mut rx) = match datalink::channel(&interface, &Default::default()) {
         Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
@@ -158,7 +183,12 @@ pub fn recieve_packets(interface: NetworkInterface, tx: Sender<Ipv4Addr>) {
                     let temppacket = ArpPacket::new(packet.payload()).unwrap();
                     // Check to see if the Arp Operation is reply
                     if temppacket.get_operation() == ArpOperations::Reply {
-                        tx.send(temppacket.get_sender_proto_addr());
+                        let _ = tx.send(Responder {
+                            ip: temppacket.get_sender_proto_addr(),
+                            mac: temppacket.get_sender_hw_addr(),
+                            interface: interface.name.clone(),
+                            latency: started.elapsed(),
+                        });
                         now = Instant::now();
                     }
                 }
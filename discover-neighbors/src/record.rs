@@ -0,0 +1,238 @@
+use pnet::util::MacAddr;
+
+use std::fs::{DirBuilder, File};
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use networking::Responder;
+
+const STATE_DIR: &str = "/var/lib/dct-node";
+
+// The outcome of the last discovery run on this node, kept in /var/lib/dct-node/discovery so
+// the show-data action can explain what this node saw, even after a reboot. The file is plain
+// text, one fact per line:
+//
+//   epoch 3
+//   started 1476374400
+//   interface eth0
+//   target host2 10.0.0.2
+//   responder 10.0.0.2 52:54:0:12:34:56 eth0 1.250
+//   neighbor host2
+//   published host2
+//   confirmed 1
+//   finished 1476374412
+//
+// Responder latencies are in milliseconds. A run that failed also has an error line saying why,
+// such as "error No network interfaces could be opened".
+pub struct DiscoveryRecord {
+    // The controller's discovery round this run belongs to, 0 for on-demand runs
+    pub epoch: u64,
+    // Unix time the run started and finished
+    pub started: u64,
+    pub finished: u64,
+    pub interfaces: Vec<String>,
+    // The hosts probed, with the address each was probed at
+    pub targets: Vec<(String, Ipv4Addr)>,
+    pub responders: Vec<Responder>,
+    pub neighbors: Vec<String>,
+    // What was set as neighbors on the controller relation, if anything
+    pub published: Option<String>,
+    // Whether the controller saw the published value
+    pub confirmed: bool,
+    // Why the run failed, if it did
+    pub error: Option<String>,
+}
+
+impl DiscoveryRecord {
+    pub fn new() -> DiscoveryRecord {
+        DiscoveryRecord {
+            epoch: 0,
+            started: unix_time(),
+            finished: 0,
+            interfaces: Vec::new(),
+            targets: Vec::new(),
+            responders: Vec::new(),
+            neighbors: Vec::new(),
+            published: None,
+            confirmed: false,
+            error: None,
+        }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut file = try!(File::create(record_path()).map_err(|e| e.to_string()));
+        try!(file.write_all(self.to_text().as_bytes()).map_err(|e| e.to_string()));
+        Ok(())
+    }
+
+    pub fn load() -> Result<DiscoveryRecord, String> {
+        let mut text = String::new();
+        try!(File::open(record_path())
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("No discovery has been recorded on this node: {}", e)));
+        DiscoveryRecord::from_text(&text)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("epoch {}\nstarted {}\n", self.epoch, self.started);
+        for interface in &self.interfaces {
+            text.push_str(&format!("interface {}\n", interface));
+        }
+        for &(ref host, ip) in &self.targets {
+            text.push_str(&format!("target {} {}\n", host, ip));
+        }
+        for responder in &self.responders {
+            text.push_str(&format!("responder {} {} {} {:.3}\n",
+                                   responder.ip,
+                                   responder.mac,
+                                   responder.interface,
+                                   milliseconds(responder.latency)));
+        }
+        for neighbor in &self.neighbors {
+            text.push_str(&format!("neighbor {}\n", neighbor));
+        }
+        if let Some(ref published) = self.published {
+            text.push_str(&format!("published {}\n", published));
+            text.push_str(&format!("confirmed {}\n", if self.confirmed { 1 } else { 0 }));
+        }
+        if let Some(ref error) = self.error {
+            text.push_str(&format!("error {}\n", error));
+        }
+        text.push_str(&format!("finished {}\n", self.finished));
+        text
+    }
+
+    pub fn from_text(text: &str) -> Result<DiscoveryRecord, String> {
+        let mut record = DiscoveryRecord::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match (fields.first().copied(), fields.len()) {
+                (Some("epoch"), 2) => record.epoch = try!(number(fields[1])),
+                (Some("started"), 2) => record.started = try!(number(fields[1])),
+                (Some("finished"), 2) => record.finished = try!(number(fields[1])),
+                (Some("interface"), 2) => record.interfaces.push(fields[1].to_string()),
+                (Some("target"), 3) => {
+                    record.targets.push((fields[1].to_string(), try!(number(fields[2]))))
+                }
+                (Some("responder"), 5) => {
+                    let latency: f64 = try!(number(fields[4]));
+                    record.responders.push(Responder {
+                        ip: try!(number(fields[1])),
+                        mac: try!(mac_address(fields[2])),
+                        interface: fields[3].to_string(),
+                        latency: Duration::new((latency / 1000.0) as u64,
+                                               ((latency % 1000.0) * 1000000.0) as u32),
+                    });
+                }
+                (Some("neighbor"), 2) => record.neighbors.push(fields[1].to_string()),
+                (Some("published"), _) => record.published = Some(fields[1..].join(" ")),
+                (Some("confirmed"), 2) => record.confirmed = fields[1] == "1",
+                (Some("error"), _) => record.error = Some(fields[1..].join(" ")),
+                (None, _) => {}
+                _ => return Err(format!("Could not understand the discovery record line {}", line)),
+            }
+        }
+        Ok(record)
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+pub fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1000000.0
+}
+
+// The directory is only readable by the charm, like the controller's state directory
+fn record_path() -> PathBuf {
+    let directory = PathBuf::from(STATE_DIR);
+    let _ = DirBuilder::new().recursive(true).mode(0o700).create(&directory);
+    directory.join("discovery")
+}
+
+fn number<T: FromStr>(field: &str) -> Result<T, String> {
+    field.parse::<T>()
+        .map_err(|_| format!("{} is not a valid value in the discovery record", field))
+}
+
+fn mac_address(field: &str) -> Result<MacAddr, String> {
+    let octets: Vec<u8> = try!(field.split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("{} is not a MAC address", field)));
+    if octets.len() != 6 {
+        return Err(format!("{} is not a MAC address", field));
+    }
+    Ok(MacAddr(octets[0], octets[1], octets[2], octets[3], octets[4], octets[5]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> DiscoveryRecord {
+        DiscoveryRecord {
+            epoch: 3,
+            started: 1476374400,
+            finished: 1476374412,
+            interfaces: vec!["eth0".to_string(), "eth1".to_string()],
+            targets: vec![("host2".to_string(), Ipv4Addr::new(10, 0, 0, 2))],
+            responders: vec![Responder {
+                                 ip: Ipv4Addr::new(10, 0, 0, 2),
+                                 mac: MacAddr(0x52, 0x54, 0, 0x12, 0x34, 0x56),
+                                 interface: "eth0".to_string(),
+                                 latency: Duration::new(0, 1250000),
+                             }],
+            neighbors: vec!["host2".to_string()],
+            published: Some("host2 host3".to_string()),
+            confirmed: true,
+            error: Some("The neighbors set on the relation never read back the same".to_string()),
+        }
+    }
+
+    #[test]
+    fn records_read_back_the_same() {
+        let text = record().to_text();
+        assert_eq!(text,
+                   "epoch 3\nstarted 1476374400\ninterface eth0\ninterface eth1\n\
+                    target host2 10.0.0.2\nresponder 10.0.0.2 52:54:0:12:34:56 eth0 1.250\n\
+                    neighbor host2\npublished host2 host3\nconfirmed 1\n\
+                    error The neighbors set on the relation never read back the same\n\
+                    finished 1476374412\n");
+
+        let loaded = DiscoveryRecord::from_text(&text).unwrap();
+        assert_eq!(loaded.to_text(), text);
+        assert_eq!(loaded.responders[0].latency, Duration::new(0, 1250000));
+        assert_eq!(loaded.published, Some("host2 host3".to_string()));
+        assert!(loaded.confirmed);
+    }
+
+    #[test]
+    fn unpublished_runs_have_no_published_line() {
+        let mut record = record();
+        record.published = None;
+        record.error = None;
+        let loaded = DiscoveryRecord::from_text(&record.to_text()).unwrap();
+        assert_eq!(loaded.published, None);
+        assert_eq!(loaded.error, None);
+        assert!(!loaded.confirmed);
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        assert!(DiscoveryRecord::from_text("epoch three\n").is_err());
+        assert!(DiscoveryRecord::from_text("target host2 10.0.0.256\n").is_err());
+        assert!(DiscoveryRecord::from_text("target host2\n").is_err());
+        assert!(DiscoveryRecord::from_text("responder 10.0.0.2 52:54:00:12:34 eth0 1.250\n")
+            .is_err());
+        assert!(DiscoveryRecord::from_text("responder 10.0.0.2 52:54:00:12:34:zz eth0 1.250\n")
+            .is_err());
+        assert!(DiscoveryRecord::from_text("colour blue\n").is_err());
+        assert_eq!(DiscoveryRecord::from_text("\n\nepoch 2\n").unwrap().epoch, 2);
+    }
+}